@@ -0,0 +1,281 @@
+use crate::confirmation::{moderate, send_reminder_email};
+use crate::errors::{CompatErr, SpecialErrors};
+use crate::management::{
+    clean_stale, delete_key, delete_pending, list_flagged, list_keys, list_moderation,
+    list_pending, publish_key, published_key, read_moderation, read_pending, unflag_key,
+    FlaggedKey, KeyInfo, Pending, PendingInfo,
+};
+use crate::outbox::{delete_mail, list_outbox, retry_mail, QueuedMailInfo};
+use crate::settings::SETTINGS;
+use crate::utils::constant_time_eq;
+
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{
+    delete, get, post, web, FromRequest, HttpRequest, HttpResponse, ResponseError, Scope,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+use std::future::{ready, Ready};
+
+// The admin API is used by scripts, so its errors are JSON objects instead of pages
+#[derive(Debug)]
+pub struct AdminErr(CompatErr);
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    error: String,
+}
+
+impl Display for AdminErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<T> From<T> for AdminErr
+where
+    CompatErr: From<T>,
+{
+    fn from(value: T) -> Self {
+        AdminErr(CompatErr::from(value))
+    }
+}
+
+impl ResponseError for AdminErr {
+    fn status_code(&self) -> StatusCode {
+        self.0.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.0.to_string(),
+        })
+    }
+}
+
+// Malformed paths, queries and bodies are rejected before reaching the handlers
+fn malformed<E: Debug + Display + 'static>(error: E, _: &HttpRequest) -> actix_web::Error {
+    let response = HttpResponse::BadRequest().json(ErrorBody {
+        error: error.to_string(),
+    });
+    InternalError::from_response(error, response).into()
+}
+
+pub struct AdminToken;
+
+impl FromRequest for AdminToken {
+    type Error = AdminErr;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let authorized = match token {
+            Some(token) => SETTINGS
+                .admin_tokens
+                .iter()
+                .any(|allowed| constant_time_eq(allowed.as_bytes(), token.trim().as_bytes())),
+            None => false,
+        };
+        if authorized {
+            ready(Ok(AdminToken))
+        } else {
+            warn!(
+                "Rejected admin request to {} from {}",
+                req.path(),
+                req.connection_info().peer_addr().unwrap_or("<unknown>")
+            );
+            ready(Err(SpecialErrors::Unauthorized.into()))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Search {
+    search: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Key {
+    key: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Decision {
+    reason: Option<String>,
+}
+
+pub fn scope() -> Scope {
+    web::scope("/admin/api")
+        .app_data(web::JsonConfig::default().error_handler(malformed))
+        .app_data(web::PathConfig::default().error_handler(malformed))
+        .app_data(web::QueryConfig::default().error_handler(malformed))
+        .service(keys)
+        .service(key_details)
+        .service(publish)
+        .service(remove)
+        .service(pending)
+        .service(pending_request)
+        .service(cancel)
+        .service(remind)
+        .service(cleanup)
+        .service(moderation)
+        .service(moderation_request)
+        .service(approve)
+        .service(reject)
+        .service(outbox)
+        .service(retry)
+        .service(discard)
+        .service(flagged)
+        .service(unflag)
+}
+
+fn pending_info(id: String, request: Pending) -> Result<PendingInfo, AdminErr> {
+    Ok(PendingInfo {
+        address: request.address()?,
+        action: *request.action(),
+        timestamp: request.timestamp(),
+        bounce: request.bounce().map(ToString::to_string),
+        id,
+    })
+}
+
+#[get("/keys")]
+async fn keys(_: AdminToken, query: web::Query<Search>) -> web::Json<Vec<KeyInfo>> {
+    let search = query.search.as_deref().unwrap_or("").to_lowercase();
+    web::Json(
+        list_keys()
+            .into_iter()
+            .filter(|info| info.email.to_lowercase().contains(&search))
+            .collect(),
+    )
+}
+
+#[get("/keys/{email}")]
+async fn key_details(
+    _: AdminToken,
+    email: web::Path<String>,
+) -> Result<web::Json<KeyInfo>, AdminErr> {
+    Ok(web::Json(published_key(&email)?))
+}
+
+#[post("/keys")]
+async fn publish(_: AdminToken, pem: web::Json<Key>) -> Result<web::Json<KeyInfo>, AdminErr> {
+    let key = publish_key(&pem.key)?;
+    info!("Admin published the key of user {}", key.email);
+    Ok(web::Json(key))
+}
+
+#[delete("/keys/{email}")]
+async fn remove(_: AdminToken, email: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    published_key(&email)?;
+    delete_key(&email)?;
+    info!("Admin deleted the key of user {}", email);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/pending")]
+async fn pending(_: AdminToken) -> web::Json<Vec<PendingInfo>> {
+    web::Json(list_pending())
+}
+
+#[get("/pending/{id}")]
+async fn pending_request(
+    _: AdminToken,
+    id: web::Path<String>,
+) -> Result<web::Json<PendingInfo>, AdminErr> {
+    let request = read_pending(&id)?;
+    Ok(web::Json(pending_info(id.into_inner(), request)?))
+}
+
+#[delete("/pending/{id}")]
+async fn cancel(_: AdminToken, id: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    delete_pending(&id)?;
+    info!("Admin cancelled pending request {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/pending/{id}/remind")]
+async fn remind(_: AdminToken, id: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    let email = send_reminder_email(&id)?;
+    info!("Admin reminded user {} of pending request {}", email, id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/cleanup")]
+async fn cleanup(_: AdminToken) -> HttpResponse {
+    debug!("Cleaning up stale data on admin request...");
+    clean_stale(SETTINGS.max_age);
+    debug!("Cleanup completed!");
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/moderation")]
+async fn moderation(_: AdminToken) -> web::Json<Vec<PendingInfo>> {
+    web::Json(list_moderation())
+}
+
+#[get("/moderation/{id}")]
+async fn moderation_request(
+    _: AdminToken,
+    id: web::Path<String>,
+) -> Result<web::Json<PendingInfo>, AdminErr> {
+    let request = read_moderation(&id)?;
+    Ok(web::Json(pending_info(id.into_inner(), request)?))
+}
+
+#[post("/moderation/{id}/approve")]
+async fn approve(_: AdminToken, id: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    let email = moderate(&id, true, None)?;
+    info!("Admin approved the key of user {}", email);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/moderation/{id}/reject")]
+async fn reject(
+    _: AdminToken,
+    id: web::Path<String>,
+    decision: Option<web::Json<Decision>>,
+) -> Result<HttpResponse, AdminErr> {
+    let decision = decision.map(web::Json::into_inner).unwrap_or_default();
+    let email = moderate(&id, false, decision.reason.as_deref())?;
+    info!("Admin rejected the key of user {}", email);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/outbox")]
+async fn outbox(_: AdminToken) -> web::Json<Vec<QueuedMailInfo>> {
+    web::Json(list_outbox())
+}
+
+#[post("/outbox/{id}/retry")]
+async fn retry(_: AdminToken, id: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    retry_mail(&id)?;
+    info!("Admin queued email {} again", id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/outbox/{id}")]
+async fn discard(_: AdminToken, id: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    delete_mail(&id)?;
+    info!("Admin discarded email {}", id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/flagged")]
+async fn flagged(_: AdminToken) -> Result<web::Json<Vec<FlaggedKey>>, AdminErr> {
+    Ok(web::Json(list_flagged()?))
+}
+
+#[delete("/flagged/{email}")]
+async fn unflag(_: AdminToken, email: web::Path<String>) -> Result<HttpResponse, AdminErr> {
+    unflag_key(&email)?;
+    info!("Admin cleared the flag of user {}", email);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,131 @@
+use crate::errors::SpecialErrors;
+use crate::settings::{paths, SETTINGS};
+use crate::utils::sha256_hex;
+
+use anyhow::Result;
+use rust_embed::RustEmbed;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Debug builds read the files from the repository instead of embedding them
+#[derive(RustEmbed)]
+#[folder = "../assets"]
+struct Embedded;
+
+pub struct Asset {
+    pub data: Cow<'static, [u8]>,
+    pub etag: String,
+}
+
+fn assets_dir() -> Option<&'static Path> {
+    paths()
+        .assets_dir
+        .as_deref()
+        .or(SETTINGS.assets_dir.as_deref())
+}
+
+// Only files inside the override directory can be read, whatever the requested path contains
+fn override_path(path: &str) -> Option<PathBuf> {
+    let root = assets_dir()?.canonicalize().ok()?;
+    let path = root.join(path).canonicalize().ok()?;
+    (path.starts_with(&root) && path.is_file()).then_some(path)
+}
+
+pub fn get_asset(path: &str) -> Option<Asset> {
+    if let Some(path) = override_path(path) {
+        let data = fs::read(path).ok()?;
+        return Some(Asset {
+            etag: sha256_hex(&data).ok()?,
+            data: Cow::Owned(data),
+        });
+    }
+    let file = Embedded::get(path)?;
+    Some(Asset {
+        etag: file
+            .metadata
+            .sha256_hash()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        data: file.data,
+    })
+}
+
+pub fn read_asset(path: &str) -> Result<String> {
+    match get_asset(path) {
+        Some(asset) => Ok(String::from_utf8(asset.data.into_owned())?),
+        None => Err(SpecialErrors::MissingFile)?,
+    }
+}
+
+pub fn has_asset(path: &str) -> bool {
+    override_path(path).is_some() || Embedded::get(path).is_some()
+}
+
+// The names of the files directly inside `folder`, either embedded or overridden
+pub fn list_assets(folder: &str) -> BTreeSet<String> {
+    let prefix = format!("{}/", folder);
+    let mut names: BTreeSet<String> = Embedded::iter()
+        .filter_map(|name| {
+            let name = name.strip_prefix(&prefix)?;
+            (!name.contains('/')).then(|| name.to_string())
+        })
+        .collect();
+    if let Some(root) = assets_dir() {
+        if let Ok(entries) = fs::read_dir(root.join(folder)) {
+            names.extend(
+                entries
+                    .flatten()
+                    .filter(|entry| entry.path().is_file())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned()),
+            );
+        }
+    }
+    names
+}
+
+// Only plain names are allowed, so that requests can't leave the web interface folder
+fn is_page_path(filename: &str) -> bool {
+    Path::new(filename)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::RootDir))
+}
+
+// Finds the file of the web interface at `filename`, or the index of the folder
+pub fn resolve_page(filename: &str) -> Option<(String, Asset)> {
+    if !is_page_path(filename) {
+        return None;
+    }
+    let path = format!("webpage/{}", filename.trim_matches('/'));
+    let index = format!("{}/index.html", path.trim_end_matches('/'));
+    [path, index]
+        .into_iter()
+        .find_map(|path| Some((path.clone(), get_asset(&path)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_paths_leaving_the_web_interface() {
+        assert!(is_page_path(""));
+        assert!(is_page_path("/"));
+        assert!(is_page_path("index.html"));
+        assert!(is_page_path("_astro/index.css"));
+        assert!(is_page_path("status/"));
+        assert!(!is_page_path(".."));
+        assert!(!is_page_path("../config.toml"));
+        assert!(!is_page_path("/../private/notes.txt"));
+        assert!(!is_page_path("status/../../locales/en.toml"));
+        assert!(!is_page_path("./index.html"));
+    }
+
+    #[test]
+    fn does_not_resolve_files_outside_the_web_interface() {
+        assert!(resolve_page("../locales/en.toml").is_none());
+        assert!(resolve_page("/../mail/en/add.txt").is_none());
+    }
+}
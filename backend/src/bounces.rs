@@ -0,0 +1,298 @@
+use crate::log_err;
+use crate::management::{flag_key, list_pending, mark_bounced};
+use crate::settings::{BounceSettings, ERROR_TEXT};
+use crate::utils::{read_key, token_id};
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use mailparse::{parse_headers, parse_mail, MailHeaderMap, ParsedMail};
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Debug)]
+struct Bounce {
+    recipient: String,
+    status: String,
+    diagnostic: Option<String>,
+}
+
+impl Bounce {
+    fn is_permanent(&self) -> bool {
+        self.status.starts_with('5')
+    }
+
+    fn reason(&self) -> String {
+        match &self.diagnostic {
+            Some(diagnostic) => format!("{} ({})", self.status, diagnostic),
+            None => self.status.clone(),
+        }
+    }
+}
+
+fn is_delivery_report(mail: &ParsedMail) -> bool {
+    mail.ctype.mimetype == "multipart/report"
+        && mail
+            .ctype
+            .params
+            .get("report-type")
+            .is_some_and(|report| report.eq_ignore_ascii_case("delivery-status"))
+}
+
+fn parse_fields(block: &str) -> HashMap<String, String> {
+    match parse_headers(block.as_bytes()) {
+        Ok((headers, _)) => headers
+            .iter()
+            .map(|header| (header.get_key().to_lowercase(), header.get_value()))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn parse_status(body: &str) -> Vec<Bounce> {
+    let body = body.replace("\r\n", "\n");
+    // The first block holds the per-message fields, the following ones the per-recipient fields
+    body.split("\n\n")
+        .skip(1)
+        .map(parse_fields)
+        .filter(|fields| {
+            fields
+                .get("action")
+                .is_some_and(|action| action.trim().eq_ignore_ascii_case("failed"))
+        })
+        .filter_map(|fields| {
+            let recipient = fields
+                .get("final-recipient")
+                .or_else(|| fields.get("original-recipient"))?;
+            let recipient = recipient.rsplit(';').next()?.trim();
+            Some(Bounce {
+                recipient: recipient
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_lowercase(),
+                status: fields.get("status")?.trim().to_string(),
+                diagnostic: fields
+                    .get("diagnostic-code")
+                    .map(|code| code.trim().to_string()),
+            })
+        })
+        .collect()
+}
+
+fn extract_tokens(text: &str) -> Vec<String> {
+    text.match_indices("token=")
+        .map(|(index, pattern)| {
+            text[index + pattern.len()..]
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .collect::<String>()
+        })
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+fn returned_tokens(part: &ParsedMail) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let Ok(raw) = part.get_body_raw() else {
+        return tokens;
+    };
+    let Ok(returned) = parse_mail(&raw) else {
+        return tokens;
+    };
+    for text in returned
+        .parts()
+        .filter(|part| part.ctype.mimetype.starts_with("text/"))
+    {
+        if let Ok(body) = text.get_body() {
+            tokens.extend(extract_tokens(&body));
+        }
+    }
+    tokens
+}
+
+fn handle_bounce(bounce: &Bounce, ids: &[String], settings: &BounceSettings) {
+    if !bounce.is_permanent() {
+        debug!(
+            "Ignoring temporary failure for {}: {}",
+            bounce.recipient,
+            bounce.reason()
+        );
+        return;
+    }
+    info!("Email to {} bounced: {}", bounce.recipient, bounce.reason());
+    for request in list_pending().into_iter().filter(|request| {
+        ids.contains(&request.id) || request.address.to_lowercase() == bounce.recipient
+    }) {
+        if mark_bounced(&request.id, &bounce.reason()).is_err() {
+            warn!("Unable to mark request {} as bounced", request.id);
+        }
+    }
+    if settings.flag_keys
+        && read_key(&bounce.recipient).is_ok()
+        && log_err!(flag_key(&bounce.recipient, &bounce.reason()), warn).is_ok()
+    {
+        info!("Flagged the key of user {} for review", bounce.recipient);
+    }
+}
+
+fn handle_message(content: &[u8], settings: &BounceSettings) -> Result<bool> {
+    let mail = parse_mail(content)?;
+    if !is_delivery_report(&mail) {
+        debug!(
+            "Skipping message {}, since it's not a delivery report",
+            mail.headers
+                .get_first_value("Message-ID")
+                .unwrap_or_default()
+        );
+        return Ok(false);
+    }
+    let mut bounces = Vec::new();
+    let mut ids = Vec::new();
+    for part in mail.parts() {
+        match part.ctype.mimetype.as_str() {
+            "message/delivery-status" => bounces.extend(parse_status(&part.get_body()?)),
+            "message/rfc822" => ids.extend(
+                returned_tokens(part)
+                    .iter()
+                    .filter_map(|token| token_id(token).ok()),
+            ),
+            _ => {}
+        }
+    }
+    for bounce in &bounces {
+        handle_bounce(bounce, &ids, settings);
+    }
+    Ok(!bounces.is_empty())
+}
+
+pub fn process_bounces(settings: &BounceSettings) -> Result<usize> {
+    let new = settings.maildir.join("new");
+    let cur = settings.maildir.join("cur");
+    log_err!(fs::create_dir_all(&cur), warn)?;
+    let mut processed = 0;
+    for path in log_err!(fs::read_dir(&new), warn)?
+        .flatten()
+        .map(|entry| entry.path())
+    {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let bounced = match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| handle_message(&content, settings))
+        {
+            Ok(bounced) => bounced,
+            Err(error) => {
+                warn!("{} {}: {}", ERROR_TEXT, path.display(), error);
+                false
+            }
+        };
+        // Move the message out of new, marking the processed reports as seen
+        let flags = if bounced { "2,S" } else { "2," };
+        log_err!(
+            fs::rename(&path, cur.join(format!("{}:{}", name, flags))),
+            warn
+        )?;
+        if bounced {
+            processed += 1;
+        }
+    }
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A report of Postfix for an email to two recipients, shortened
+    const REPORT: &str = "From: MAILER-DAEMON@mail.example.org (Mail Delivery System)\r
+Subject: Undelivered Mail Returned to Sender\r
+To: key-submission@example.org\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=delivery-status;\r
+\tboundary=\"8A1B2C3D4E.1700000000/mail.example.org\"\r
+\r
+This is a MIME-encapsulated message.\r
+\r
+--8A1B2C3D4E.1700000000/mail.example.org\r
+Content-Description: Notification\r
+Content-Type: text/plain; charset=us-ascii\r
+\r
+I'm sorry to have to inform you that your message could not\r
+be delivered to one or more recipients.\r
+\r
+--8A1B2C3D4E.1700000000/mail.example.org\r
+Content-Description: Delivery report\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mail.example.org\r
+X-Postfix-Queue-ID: 8A1B2C3D4E\r
+Arrival-Date: Tue, 14 Nov 2023 22:13:20 +0100 (CET)\r
+\r
+Final-Recipient: rfc822; Alice@Example.org\r
+Original-Recipient: rfc822;alice@example.org\r
+Action: failed\r
+Status: 5.1.1\r
+Remote-MTA: dns; mx.example.org\r
+Diagnostic-Code: smtp; 550 5.1.1 <Alice@Example.org>: Recipient address\r
+    rejected: User unknown in virtual mailbox table\r
+\r
+Final-Recipient: rfc822; bob@example.org\r
+Action: delayed\r
+Status: 4.4.1\r
+Diagnostic-Code: X-Postfix; connect to mx.example.org[192.0.2.1]:25: Connection\r
+    timed out\r
+\r
+Original-Recipient: rfc822;<carol@example.org>\r
+Action: failed\r
+Status: 5.2.2\r
+\r
+--8A1B2C3D4E.1700000000/mail.example.org\r
+Content-Description: Undelivered Message\r
+Content-Type: message/rfc822\r
+\r
+From: key-submission@example.org\r
+To: alice@example.org\r
+Subject: Please confirm to add your public key\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Confirm the request: https://wkd.example.org/confirm?token=AbC123xyz\r
+\r
+--8A1B2C3D4E.1700000000/mail.example.org--\r
+";
+
+    #[test]
+    fn parses_failed_recipients() {
+        let mail = parse_mail(REPORT.as_bytes()).unwrap();
+        assert!(is_delivery_report(&mail));
+        let status = mail
+            .parts()
+            .find(|part| part.ctype.mimetype == "message/delivery-status")
+            .unwrap();
+        let bounces = parse_status(&status.get_body().unwrap());
+        assert_eq!(bounces.len(), 2);
+        assert_eq!(bounces[0].recipient, "alice@example.org");
+        assert_eq!(bounces[0].status, "5.1.1");
+        assert!(bounces[0].is_permanent());
+        assert!(bounces[0]
+            .diagnostic
+            .as_deref()
+            .is_some_and(|diagnostic| diagnostic.contains("User unknown")));
+        assert_eq!(bounces[1].recipient, "carol@example.org");
+        assert_eq!(bounces[1].reason(), "5.2.2");
+    }
+
+    #[test]
+    fn finds_returned_tokens() {
+        let mail = parse_mail(REPORT.as_bytes()).unwrap();
+        let returned = mail
+            .parts()
+            .find(|part| part.ctype.mimetype == "message/rfc822")
+            .unwrap();
+        assert_eq!(returned_tokens(returned), ["AbC123xyz"]);
+    }
+
+    #[test]
+    fn skips_other_messages() {
+        let mail = parse_mail(b"Content-Type: multipart/report; report-type=disposition-notification; boundary=x\r\n\r\n--x--\r\n").unwrap();
+        assert!(!is_delivery_report(&mail));
+        assert!(parse_status("Reporting-MTA: dns; mail.example.org\n").is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::bounces::process_bounces;
+use crate::confirmation::{moderate, send_reminder_email};
+use crate::errors::SpecialErrors;
+use crate::i18n::is_supported;
+use crate::management::{
+    clean_stale, delete_key, delete_pending, list_flagged, list_keys, list_moderation,
+    list_pending, publish_key, published_key, unflag_key, PendingInfo,
+};
+use crate::outbox::{delete_mail, list_outbox, retry_mail};
+use crate::settings::{build_mailer, load_settings, Paths, Settings, SETTINGS};
+use crate::utils::{email_to_file_path, encode_local, read_file};
+
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use clap::{Parser, Subcommand};
+use lettre::message::Mailbox;
+use log::info;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "A simple web key directory server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub paths: Paths,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the server (default)
+    Serve,
+    /// Publish the key in the given file without email confirmation
+    Add { file: PathBuf },
+    /// Remove the published key of the given address
+    Remove { email: String },
+    /// List the addresses with a published key
+    List,
+    /// Show the details of the key published for the given address
+    Show { email: String },
+    /// Print the path where the key of the given address is published
+    Hash {
+        email: String,
+        /// Only print the zbase32 encoded local part
+        #[arg(long)]
+        local: bool,
+    },
+    /// Manage the requests awaiting confirmation
+    #[command(subcommand)]
+    Pending(PendingCommand),
+    /// Manage the requests awaiting the approval of an administrator
+    #[command(subcommand)]
+    Moderation(ModerationCommand),
+    /// Manage the outgoing emails
+    #[command(subcommand)]
+    Outbox(OutboxCommand),
+    /// Process the bounced emails and manage the flagged keys
+    #[command(subcommand)]
+    Bounces(BouncesCommand),
+    /// Check that the configuration file is valid
+    CheckConfig,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PendingCommand {
+    /// List the requests awaiting confirmation
+    List,
+    /// Send a reminder of the given request to its owner
+    Remind { id: String },
+    /// Remove the requests awaiting confirmation
+    Purge {
+        /// Only remove the requests older than `max_age`
+        #[arg(long)]
+        stale: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ModerationCommand {
+    /// List the requests awaiting approval
+    List,
+    /// Publish the key of the given request and notify its owner
+    Approve { id: String },
+    /// Discard the given request and notify its owner
+    Reject {
+        id: String,
+        /// The reason to include in the notification
+        #[arg(long)]
+        reason: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OutboxCommand {
+    /// List the emails awaiting delivery and the failed ones
+    List,
+    /// Queue the given email again, resetting its attempts
+    Retry { id: String },
+    /// Discard the given email
+    Delete { id: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BouncesCommand {
+    /// Process the delivery reports in the bounce maildir
+    Process,
+    /// List the published keys whose address bounced
+    Flagged,
+    /// Clear the flag of the given address
+    Unflag { email: String },
+}
+
+fn print_requests(requests: Vec<PendingInfo>) {
+    for request in requests {
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            request.id,
+            request.action,
+            request.address,
+            format_timestamp(request.timestamp),
+            request.bounce.unwrap_or_default()
+        );
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+fn check_config(settings: &Settings) -> Result<()> {
+    if settings.allowed_domains.is_empty() {
+        println!("Warning: no domains are allowed, every submission will be rejected");
+    }
+    if settings
+        .admin_tokens
+        .iter()
+        .any(|token| token.trim().is_empty())
+    {
+        println!("Warning: empty admin tokens are configured");
+    }
+    if !is_supported(&settings.default_locale.to_lowercase()) {
+        println!(
+            "Warning: no translations are available for the default locale {}",
+            settings.default_locale
+        );
+    }
+    if settings
+        .pow_difficulty
+        .is_some_and(|difficulty| difficulty == 0 || difficulty > 32)
+    {
+        println!("Warning: the proof of work difficulty should be between 1 and 32 bits");
+    }
+    if settings.mail_settings.mail_from.parse::<Mailbox>().is_err() {
+        Err(SpecialErrors::MalformedEmail)?
+    }
+    build_mailer(&settings.mail_settings)?;
+    Ok(())
+}
+
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("The server is started by main"),
+        Command::Add { file } => {
+            let key = publish_key(&read_file(&file)?)?;
+            info!("Published the key of user {} from the cli", key.email);
+            println!("Published {} ({})", key.email, key.fingerprint);
+        }
+        Command::Remove { email } => {
+            published_key(&email)?;
+            delete_key(&email)?;
+            info!("Deleted the key of user {} from the cli", email);
+            println!("Removed {}", email);
+        }
+        Command::List => {
+            for key in list_keys() {
+                println!("{}", key.email);
+            }
+        }
+        Command::Show { email } => {
+            let key = published_key(&email)?;
+            println!("Email:       {}", key.email);
+            println!("Fingerprint: {}", key.fingerprint);
+            for user_id in &key.user_ids {
+                println!("User ID:     {}", user_id);
+            }
+            println!("Created:     {}", format_timestamp(key.created));
+            match key.expires {
+                Some(expires) => println!("Expires:     {}", format_timestamp(expires)),
+                None => println!("Expires:     never"),
+            }
+            println!("Valid:       {}", key.valid);
+        }
+        Command::Hash { email, local } => {
+            let path = email_to_file_path(&email)?;
+            if local {
+                let local_part = email.split('@').next().unwrap_or_default();
+                println!("{}", encode_local(local_part));
+            } else {
+                println!("{}", path.display());
+            }
+        }
+        Command::Pending(PendingCommand::List) => print_requests(list_pending()),
+        Command::Pending(PendingCommand::Remind { id }) => {
+            let email = send_reminder_email(&id)?;
+            println!("Reminded {}", email);
+        }
+        Command::Pending(PendingCommand::Purge { stale }) => {
+            if stale {
+                clean_stale(SETTINGS.max_age);
+            } else {
+                for request in list_pending() {
+                    delete_pending(&request.id)?;
+                }
+            }
+            info!("Purged pending requests from the cli");
+        }
+        Command::Moderation(ModerationCommand::List) => print_requests(list_moderation()),
+        Command::Moderation(ModerationCommand::Approve { id }) => {
+            let email = moderate(&id, true, None)?;
+            info!("Approved the key of user {} from the cli", email);
+            println!("Approved {}", email);
+        }
+        Command::Moderation(ModerationCommand::Reject { id, reason }) => {
+            let email = moderate(&id, false, reason.as_deref())?;
+            info!("Rejected the key of user {} from the cli", email);
+            println!("Rejected {}", email);
+        }
+        Command::Outbox(OutboxCommand::List) => {
+            for mail in list_outbox() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    mail.id,
+                    if mail.failed { "failed" } else { "queued" },
+                    mail.to.join(", "),
+                    mail.attempts,
+                    mail.last_error.unwrap_or_default()
+                );
+            }
+        }
+        Command::Outbox(OutboxCommand::Retry { id }) => {
+            retry_mail(&id)?;
+            println!("Queued {}", id);
+        }
+        Command::Outbox(OutboxCommand::Delete { id }) => {
+            delete_mail(&id)?;
+            info!("Discarded email {} from the cli", id);
+            println!("Deleted {}", id);
+        }
+        Command::Bounces(BouncesCommand::Process) => match SETTINGS.bounces.as_ref() {
+            Some(settings) => {
+                let count = process_bounces(settings)?;
+                println!("Processed {} bounces", count);
+            }
+            None => println!("Bounce processing is not configured"),
+        },
+        Command::Bounces(BouncesCommand::Flagged) => {
+            for key in list_flagged()? {
+                println!(
+                    "{}\t{}\t{}",
+                    key.email,
+                    format_timestamp(key.timestamp),
+                    key.reason
+                );
+            }
+        }
+        Command::Bounces(BouncesCommand::Unflag { email }) => {
+            unflag_key(&email)?;
+            info!("Cleared the flag of user {} from the cli", email);
+            println!("Unflagged {}", email);
+        }
+        Command::CheckConfig => {
+            check_config(&load_settings()?)?;
+            println!("The configuration is valid");
+        }
+    }
+    Ok(())
+}
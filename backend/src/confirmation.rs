@@ -1,86 +1,227 @@
-use chrono::Utc;
-use lettre::message::header::ContentType;
-use log::{debug, error, warn};
+use chrono::{TimeZone, Utc};
+use log::{debug, warn};
 
 use crate::errors::SpecialErrors;
-use crate::management::{delete_key, Action, Pending};
-use crate::settings::{MAILER, SETTINGS};
-use crate::utils::{get_email_from_cert, insert_key, parse_pem, read_file};
-use crate::{log_err, pending_path, validate_cert};
+use crate::i18n::tr_in;
+use crate::mail::{build_mail, mail_to, render_template, send_mail, Template};
+use crate::management::{
+    approve_request, claim_pending, consume_pending, delete_key, delete_pending, find_pending,
+    queue_for_moderation, read_moderation, read_pending, read_pending_token, reject_request,
+    release_pending, rotate_pending, touch_pending, Action, Pending,
+};
+use crate::proof::verify_challenge;
+use crate::settings::SETTINGS;
+use crate::utils::{
+    extract_domain, get_email_from_cert, insert_key, parse_pem, requires_approval, token_id,
+};
+use crate::{log_err, validate_cert};
 use anyhow::Result;
+use minijinja::context;
 
-use lettre::{AsyncTransport, Message};
-use std::fs;
-use std::path::Path;
+use sequoia_openpgp::Cert;
 
-pub fn confirm_action(token: &str) -> Result<(Action, String)> {
-    let pending_path = pending_path().join(token);
-    let content = log_err!(read_file(&pending_path), debug)?;
-    let key = log_err!(toml::from_str::<Pending>(&content), warn)?;
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Completed,
+    AwaitingApproval,
+}
+
+pub fn confirm_action(token: &str, signature: Option<&str>) -> Result<(Action, String, Outcome)> {
+    let (id, key) = read_pending_token(token)?;
     if Utc::now().timestamp() - key.timestamp() > SETTINGS.max_age {
-        log_err!(fs::remove_file(&pending_path), warn)?;
-        debug!("Token {} was stale", token);
+        delete_pending(&id)?;
+        debug!("Request {} was stale", id);
         Err(SpecialErrors::ExpiredRequest)?
     } else {
-        let address = match key.action() {
-            Action::Add => {
-                let cert = parse_pem(key.data())?;
-                let validcert = validate_cert!(cert)?;
-                let email = get_email_from_cert(&validcert)?;
-                log_err!(insert_key(&validcert), warn)?;
-                email
+        if let Some(challenge) = key.challenge() {
+            verify_challenge(&key.cert()?, challenge, signature.unwrap_or_default())?;
+        }
+        claim_pending(&id)?;
+        match execute_action(&id, &key) {
+            Ok((address, outcome)) => {
+                consume_pending(&id, &key)?;
+                Ok((*key.action(), address, outcome))
             }
-            Action::Delete => {
-                delete_key(key.data())?;
-                key.data().to_owned()
+            Err(error) => {
+                // Give the request back, so that the user can try again
+                release_pending(&id)?;
+                Err(error)
             }
-        };
-        fs::remove_file(&pending_path)?;
-        Ok((*key.action(), address))
+        }
     }
 }
 
-pub async fn send_confirmation_email(address: &str, action: &Action, token: &str) -> Result<()> {
-    let template = log_err!(
-        read_file(&Path::new("assets").join("mail-template.html")),
-        error,
-        true
-    )?;
-    let mut url = SETTINGS
-        .external_url
-        .join("api/")
-        .unwrap()
-        .join("confirm")
-        .unwrap();
-    url.set_query(Some(&format!("token={}", token)));
-    let email = Message::builder()
-        .from(match SETTINGS.mail_settings.mail_from.parse() {
-            Ok(mailbox) => mailbox,
-            Err(_) => {
-                panic!("Unable to parse the email in the settings!")
+fn execute_action(id: &str, key: &Pending) -> Result<(String, Outcome)> {
+    match key.action() {
+        Action::Add => {
+            let cert = parse_pem(key.data())?;
+            let validcert = validate_cert!(cert)?;
+            let email = get_email_from_cert(&validcert)?;
+            if requires_approval(&email)? {
+                queue_for_moderation(id)?;
+                debug!("Request {} was queued for moderation", id);
+                return Ok((email, Outcome::AwaitingApproval));
             }
-        })
-        .to(match log_err!(address.parse(), debug) {
-            Ok(mbox) => mbox,
-            Err(_) => Err(SpecialErrors::MalformedEmail)?,
-        })
-        .subject(
-            SETTINGS
-                .mail_settings
-                .mail_subject
-                .replace("%a", &action.to_string().to_lowercase()),
-        )
-        .header(ContentType::TEXT_HTML)
-        .body(
-            template
-                .replace("{{%u}}", url.as_ref())
-                .replace("{{%a}}", &action.to_string().to_lowercase()),
-        );
+            log_err!(insert_key(&validcert), warn)?;
+            Ok((email, Outcome::Completed))
+        }
+        Action::Delete => {
+            delete_key(key.data())?;
+            Ok((key.data().to_owned(), Outcome::Completed))
+        }
+    }
+}
+
+fn send_request_email(
+    template: Template,
+    address: &str,
+    action: &Action,
+    token: &str,
+    cert: &Cert,
+    timestamp: i64,
+    locale: &str,
+) -> Result<()> {
+    let mut url = SETTINGS.external_url.join("confirm").unwrap();
+    url.set_query(Some(&format!("token={}", token)));
+    let mut cancel_url = SETTINGS.external_url.join("cancel").unwrap();
+    cancel_url.set_query(Some(&format!("token={}", token)));
+    let expiry = match Utc.timestamp_opt(timestamp + SETTINGS.max_age, 0).single() {
+        Some(expiry) => expiry.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => String::new(),
+    };
+    let user_ids: Vec<String> = cert
+        .userids()
+        .map(|uid| String::from_utf8_lossy(uid.value()).into_owned())
+        .collect();
+    let action_name = tr_in(
+        locale,
+        &format!("actions.{}", action.to_string().to_lowercase()),
+    );
+    let subject = match &SETTINGS.mail_settings.mail_subject {
+        Some(subject) => subject.replace("%a", &action_name),
+        None => template.subject(locale),
+    };
+    let builder = mail_to(address)?.subject(subject);
+    let body = render_template(
+        template,
+        locale,
+        context! {
+            url => url.as_str(),
+            cancel_url => cancel_url.as_str(),
+            action => action_name,
+            address => address,
+            domain => extract_domain(address)?,
+            fingerprint => cert.fingerprint().to_spaced_hex(),
+            user_ids => user_ids,
+            expires => expiry,
+        },
+    )?;
+    let recipient = SETTINGS.mail_settings.encrypt.then_some(cert);
+    let email = build_mail(builder, body, recipient)?;
+
+    send_mail(email)
+}
+
+pub fn send_confirmation_email(
+    address: &str,
+    action: &Action,
+    token: &str,
+    cert: &Cert,
+    locale: &str,
+) -> Result<()> {
+    let timestamp = Utc::now().timestamp();
+    send_request_email(
+        Template::from(*action),
+        address,
+        action,
+        token,
+        cert,
+        timestamp,
+        locale,
+    )
+}
+
+pub fn send_reminder_email(id: &str) -> Result<String> {
+    let pending = read_pending(id)?;
+    let address = pending.address()?;
+    // Only the digest of the previous token is known, so a new one is always issued
+    let token = rotate_pending(id)?;
+    send_request_email(
+        Template::Reminder,
+        &address,
+        pending.action(),
+        &token,
+        &pending.cert()?,
+        pending.timestamp(),
+        &pending.locale(),
+    )?;
+    touch_pending(&token_id(&token)?)?;
+    Ok(address)
+}
+
+fn resend_request(id: &str) -> Result<()> {
+    let pending = read_pending(id)?;
+    let now = Utc::now().timestamp();
+    if now - pending.timestamp() > SETTINGS.max_age {
+        Err(SpecialErrors::ExpiredRequest)?
+    }
+    let elapsed = now - pending.last_sent();
+    if elapsed < SETTINGS.resend_interval {
+        debug!("Resend of request {} was rate limited", id);
+        Err(SpecialErrors::RateLimited(
+            SETTINGS.resend_interval - elapsed,
+        ))?
+    }
+    send_reminder_email(id)?;
+    Ok(())
+}
 
-    let email = log_err!(email, warn)?;
+pub fn resend_confirmation(address: &str, action: Action) -> Result<()> {
+    match find_pending(address, action) {
+        Some(id) => resend_request(&id),
+        None => Err(SpecialErrors::MissingFile)?,
+    }
+}
 
-    match log_err!(MAILER.send(email).await, warn) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(SpecialErrors::MailErr)?,
+fn send_decision_email(
+    address: &str,
+    approved: bool,
+    reason: Option<&str>,
+    locale: &str,
+) -> Result<()> {
+    let template = if approved {
+        Template::Approved
+    } else {
+        Template::Rejected
+    };
+    let reason = reason.map(|reason| format!("{} {}", tr_in(locale, "mail.reason"), reason));
+    let body = render_template(
+        template,
+        locale,
+        context! {
+            address => address,
+            domain => extract_domain(address)?,
+            reason => reason,
+        },
+    )?;
+    let email = build_mail(
+        mail_to(address)?.subject(template.subject(locale)),
+        body,
+        None,
+    )?;
+
+    send_mail(email)
+}
+
+pub fn moderate(id: &str, approved: bool, reason: Option<&str>) -> Result<String> {
+    let locale = read_moderation(id)?.locale();
+    let email = if approved {
+        approve_request(id)?
+    } else {
+        reject_request(id)?
+    };
+    if send_decision_email(&email, approved, reason, &locale).is_err() {
+        warn!("Unable to notify user {} of the moderation decision", email);
     }
+    Ok(email)
 }
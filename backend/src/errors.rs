@@ -1,24 +1,20 @@
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
 use actix_web::{http::StatusCode, HttpResponseBuilder, ResponseError};
 use anyhow::Error;
 use std::fmt::Display;
 use thiserror::Error as DeriveError;
 
+use crate::i18n::{current_locale, translate};
 use crate::utils::return_outcome;
 
 #[macro_export]
 macro_rules! log_err {
     ($var: expr, $level: ident) => {{
         let test = $var;
-        if test.is_err() {
-            $level!(
-                "{} {}",
-                $crate::settings::ERROR_TEXT,
-                test.as_ref().unwrap_err()
-            );
-            test
-        } else {
-            test
+        if let Err(error) = &test {
+            $level!("{} {}", $crate::settings::ERROR_TEXT, error);
         }
+        test
     }};
     ($var: expr, $level: ident, $panic: expr) => {{
         let test = $var;
@@ -36,8 +32,12 @@ macro_rules! log_err {
 
 #[derive(Debug, DeriveError)]
 pub enum SpecialErrors {
+    #[error("The request was already confirmed!")]
+    AlreadyConfirmed,
     #[error("Uploaded certificate contains a secret key!")]
     ContainsSecret,
+    #[error("The request was sent from another website!")]
+    CrossOrigin,
     #[error("Could not find any primay user email in the keyblock!")]
     EmailMissing,
     #[error("The request had expired!")]
@@ -46,7 +46,13 @@ pub enum SpecialErrors {
     InexistingUser,
     #[error("The key is either expired or uses an obsolete cipher!")]
     InvalidCert,
-    #[error("Error while sending email")]
+    #[error("The challenge signature is missing or invalid!")]
+    InvalidSignature,
+    #[error("The request token is malformed!")]
+    InvalidToken,
+    #[error("The proof of work is missing, invalid or expired!")]
+    InvalidWork,
+    #[error("Error while queueing email")]
     MailErr,
     #[error("Could not parse keyblock")]
     MalformedCert,
@@ -54,8 +60,43 @@ pub enum SpecialErrors {
     MalformedEmail,
     #[error("The requested file does not exist!")]
     MissingFile,
+    #[error("The key does not contain any valid encryption subkey!")]
+    NoEncryptionKey,
+    #[error("Too many requests, try again in {0} seconds")]
+    RateLimited(i64),
+    #[error("Too many requests are already pending for this address!")]
+    TooManyPending,
     #[error("User email rejected: domain not allowed")]
     UnallowedDomain,
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+}
+
+impl SpecialErrors {
+    // The name of the message in the locale catalogs
+    pub const fn key(&self) -> &'static str {
+        match self {
+            SpecialErrors::AlreadyConfirmed => "AlreadyConfirmed",
+            SpecialErrors::ContainsSecret => "ContainsSecret",
+            SpecialErrors::CrossOrigin => "CrossOrigin",
+            SpecialErrors::EmailMissing => "EmailMissing",
+            SpecialErrors::ExpiredRequest => "ExpiredRequest",
+            SpecialErrors::InexistingUser => "InexistingUser",
+            SpecialErrors::InvalidCert => "InvalidCert",
+            SpecialErrors::InvalidSignature => "InvalidSignature",
+            SpecialErrors::InvalidToken => "InvalidToken",
+            SpecialErrors::InvalidWork => "InvalidWork",
+            SpecialErrors::MailErr => "MailErr",
+            SpecialErrors::MalformedCert => "MalformedCert",
+            SpecialErrors::MalformedEmail => "MalformedEmail",
+            SpecialErrors::MissingFile => "MissingFile",
+            SpecialErrors::NoEncryptionKey => "NoEncryptionKey",
+            SpecialErrors::RateLimited(_) => "RateLimited",
+            SpecialErrors::TooManyPending => "TooManyPending",
+            SpecialErrors::UnallowedDomain => "UnallowedDomain",
+            SpecialErrors::Unauthorized => "Unauthorized",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -73,6 +114,18 @@ impl Display for CompatErr {
     }
 }
 
+impl CompatErr {
+    pub fn localized(&self) -> String {
+        match self {
+            Self::AnyhowErr(error) => error.to_string(),
+            Self::SpecialErr(error) => {
+                translate(&current_locale(), &format!("errors.{}", error.key()))
+                    .unwrap_or_else(|| error.to_string())
+            }
+        }
+    }
+}
+
 impl From<SpecialErrors> for CompatErr {
     fn from(value: SpecialErrors) -> Self {
         CompatErr::SpecialErr(value)
@@ -94,24 +147,39 @@ impl ResponseError for CompatErr {
         match self {
             Self::AnyhowErr(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::SpecialErr(error) => match error {
+                SpecialErrors::AlreadyConfirmed => StatusCode::CONFLICT,
                 SpecialErrors::ContainsSecret => StatusCode::BAD_REQUEST,
+                SpecialErrors::CrossOrigin => StatusCode::FORBIDDEN,
                 SpecialErrors::ExpiredRequest => StatusCode::BAD_REQUEST,
                 SpecialErrors::InexistingUser => StatusCode::NOT_FOUND,
                 SpecialErrors::InvalidCert => StatusCode::BAD_REQUEST,
+                SpecialErrors::InvalidSignature => StatusCode::BAD_REQUEST,
+                SpecialErrors::InvalidToken => StatusCode::BAD_REQUEST,
+                SpecialErrors::InvalidWork => StatusCode::BAD_REQUEST,
                 SpecialErrors::EmailMissing => StatusCode::BAD_REQUEST,
                 SpecialErrors::MailErr => StatusCode::INTERNAL_SERVER_ERROR,
                 SpecialErrors::MalformedCert => StatusCode::BAD_REQUEST,
                 SpecialErrors::MalformedEmail => StatusCode::BAD_REQUEST,
                 SpecialErrors::MissingFile => StatusCode::NOT_FOUND,
+                SpecialErrors::NoEncryptionKey => StatusCode::BAD_REQUEST,
+                SpecialErrors::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+                SpecialErrors::TooManyPending => StatusCode::CONFLICT,
                 SpecialErrors::UnallowedDomain => StatusCode::UNAUTHORIZED,
+                SpecialErrors::Unauthorized => StatusCode::UNAUTHORIZED,
             },
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        match return_outcome(Err(self)) {
+        let mut response = match return_outcome(Err(self)) {
             Ok(httpbuilder) => httpbuilder,
-            Err(_) => HttpResponseBuilder::new(self.status_code()).body(self.to_string()),
+            Err(_) => HttpResponseBuilder::new(self.status_code()).body(self.localized()),
+        };
+        if let Self::SpecialErr(SpecialErrors::RateLimited(seconds)) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(*seconds));
         }
+        response
     }
 }
@@ -0,0 +1,117 @@
+use crate::assets::{list_assets, read_asset};
+use crate::settings::{ERROR_TEXT, SETTINGS};
+
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+type Catalog = HashMap<String, HashMap<String, String>>;
+
+tokio::task_local! {
+    pub static LOCALE: String;
+}
+
+fn get_catalogs() -> HashMap<String, Catalog> {
+    debug!("Loading locale catalogs...");
+    let mut catalogs = HashMap::new();
+    for name in list_assets("locales") {
+        let Some(locale) = name.strip_suffix(".toml") else {
+            continue;
+        };
+        let path = format!("locales/{}", name);
+        let catalog =
+            read_asset(&path).and_then(|content| Ok(toml::from_str::<Catalog>(&content)?));
+        match catalog {
+            Ok(catalog) => {
+                catalogs.insert(locale.to_lowercase(), catalog);
+            }
+            Err(error) => warn!("{} {}: {}", ERROR_TEXT, path, error),
+        }
+    }
+    catalogs
+}
+
+static CATALOGS: Lazy<HashMap<String, Catalog>> = Lazy::new(get_catalogs);
+
+pub fn is_supported(locale: &str) -> bool {
+    CATALOGS.contains_key(locale)
+}
+
+pub fn default_locale() -> String {
+    SETTINGS.default_locale.to_lowercase()
+}
+
+pub fn current_locale() -> String {
+    LOCALE
+        .try_with(|locale| locale.to_owned())
+        .unwrap_or_else(|_| default_locale())
+}
+
+fn lookup(locale: &str, key: &str) -> Option<String> {
+    let (section, name) = key.split_once('.')?;
+    CATALOGS.get(locale)?.get(section)?.get(name).cloned()
+}
+
+pub fn translate(locale: &str, key: &str) -> Option<String> {
+    lookup(locale, key).or_else(|| lookup(&default_locale(), key))
+}
+
+pub fn tr_in(locale: &str, key: &str) -> String {
+    translate(locale, key).unwrap_or_else(|| key.to_string())
+}
+
+pub fn tr(key: &str) -> String {
+    tr_in(&current_locale(), key)
+}
+
+fn supported(tag: &str) -> Option<String> {
+    let tag = tag.to_lowercase();
+    if is_supported(&tag) {
+        return Some(tag);
+    }
+    let primary = tag.split('-').next()?;
+    is_supported(primary).then(|| primary.to_string())
+}
+
+pub fn negotiate(accept_language: Option<&str>) -> String {
+    let mut candidates: Vec<(f32, &str)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut parameters = range.split(';');
+            let tag = parameters.next()?.trim();
+            let quality = parameters
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0).then_some((quality, tag))
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    candidates
+        .into_iter()
+        .find_map(|(_, tag)| supported(tag))
+        .unwrap_or_else(default_locale)
+}
+
+pub fn translate_page(page: &str, locale: &str) -> String {
+    let mut translated = String::with_capacity(page.len());
+    let mut rest = page;
+    while let Some(start) = rest.find("((%t:") {
+        translated.push_str(&rest[..start]);
+        let placeholder = &rest[start + 5..];
+        match placeholder.find("))") {
+            Some(end) => {
+                translated.push_str(&tr_in(locale, &placeholder[..end]));
+                rest = &placeholder[end + 2..];
+            }
+            None => {
+                translated.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    translated.push_str(rest);
+    translated.replace("((%l))", locale)
+}
@@ -0,0 +1,286 @@
+use crate::assets::{has_asset, read_asset};
+use crate::errors::SpecialErrors;
+use crate::i18n::{default_locale, tr_in};
+use crate::log_err;
+use crate::management::Action;
+use crate::outbox::queue_mail;
+use crate::settings::{DKIM, POLICY, SERVER_KEY, SETTINGS};
+use crate::templates::render_mail;
+
+use anyhow::Result;
+use lettre::address::Envelope;
+use lettre::message::header::ContentType;
+use lettre::message::{MessageBuilder, MultiPart, SinglePart};
+use lettre::{
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use log::{debug, error, warn};
+use minijinja::Value;
+use sequoia_openpgp::armor;
+use sequoia_openpgp::cert::amalgamation::key::ValidErasedKeyAmalgamation;
+use sequoia_openpgp::crypto::KeyPair;
+use sequoia_openpgp::packet::key::PublicParts;
+use sequoia_openpgp::serialize::stream::{Armorer, Encryptor, LiteralWriter, Signer};
+use sequoia_openpgp::types::HashAlgorithm;
+use sequoia_openpgp::Cert;
+use std::io::Write;
+
+pub enum Body {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl Body {
+    fn formatted(&self) -> Vec<u8> {
+        match self {
+            Body::Single(part) => part.formatted(),
+            Body::Multi(part) => part.formatted(),
+        }
+    }
+}
+
+impl From<SinglePart> for Body {
+    fn from(part: SinglePart) -> Self {
+        Body::Single(part)
+    }
+}
+
+impl From<MultiPart> for Body {
+    fn from(part: MultiPart) -> Self {
+        Body::Multi(part)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Template {
+    Add,
+    Delete,
+    Reminder,
+    Approved,
+    Rejected,
+}
+
+impl Template {
+    const fn name(&self) -> &'static str {
+        match self {
+            Template::Add => "add",
+            Template::Delete => "delete",
+            Template::Reminder => "reminder",
+            Template::Approved => "approved",
+            Template::Rejected => "rejected",
+        }
+    }
+
+    pub fn subject(&self, locale: &str) -> String {
+        tr_in(locale, &format!("mail.subject_{}", self.name()))
+    }
+}
+
+impl From<Action> for Template {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Add => Template::Add,
+            Action::Delete => Template::Delete,
+        }
+    }
+}
+
+pub fn render_template(template: Template, locale: &str, context: Value) -> Result<MultiPart> {
+    let mut folder = format!("mail/{}", locale);
+    if !has_asset(&format!("{}/{}.txt", folder, template.name())) {
+        debug!(
+            "No mail templates for locale {}, using the default one",
+            locale
+        );
+        folder = format!("mail/{}", default_locale());
+    }
+    let path = format!("{}/{}", folder, template.name());
+    let text = log_err!(read_asset(&format!("{}.txt", path)), error)?;
+    let html = log_err!(read_asset(&format!("{}.html", path)), error)?;
+    let text = render_mail(&format!("{}.txt", template.name()), &text, context.clone())?;
+    let html = render_mail(&format!("{}.html", template.name()), &html, context)?;
+    Ok(MultiPart::alternative_plain_html(text, html))
+}
+
+pub fn mail_to(address: &str) -> Result<MessageBuilder> {
+    Ok(Message::builder()
+        .from(match SETTINGS.mail_settings.mail_from.parse() {
+            Ok(mailbox) => mailbox,
+            Err(_) => {
+                panic!("Unable to parse the email in the settings!")
+            }
+        })
+        .to(match log_err!(address.parse(), debug) {
+            Ok(mbox) => mbox,
+            Err(_) => Err(SpecialErrors::MalformedEmail)?,
+        }))
+}
+
+pub enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Stdout,
+}
+
+impl Mailer {
+    pub async fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<()> {
+        match self {
+            Mailer::Smtp(transport) => {
+                transport.send_raw(envelope, email).await?;
+            }
+            Mailer::Sendmail(transport) => {
+                transport.send_raw(envelope, email).await?;
+            }
+            Mailer::File(transport) => {
+                transport.send_raw(envelope, email).await?;
+            }
+            Mailer::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(email)?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn send_mail(email: Message) -> Result<()> {
+    match log_err!(queue_mail(&email), warn) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(SpecialErrors::MailErr)?,
+    }
+}
+
+fn encryption_keys(cert: &Cert) -> Vec<ValidErasedKeyAmalgamation<'_, PublicParts>> {
+    cert.keys()
+        .with_policy(POLICY, None)
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .for_storage_encryption()
+        .collect()
+}
+
+pub fn can_encrypt(cert: &Cert) -> bool {
+    !encryption_keys(cert).is_empty()
+}
+
+fn encrypt(cert: &Cert, data: &[u8]) -> Result<Vec<u8>> {
+    let recipients = encryption_keys(cert);
+    if recipients.is_empty() {
+        Err(SpecialErrors::NoEncryptionKey)?
+    }
+    let mut sink = Vec::new();
+    let message = sequoia_openpgp::serialize::stream::Message::new(&mut sink);
+    let message = Armorer::new(message).build()?;
+    let message = Encryptor::for_recipients(message, recipients).build()?;
+    let mut message = LiteralWriter::new(message).build()?;
+    message.write_all(data)?;
+    message.finalize()?;
+    Ok(sink)
+}
+
+fn signing_key(cert: &Cert) -> Result<KeyPair> {
+    match cert
+        .keys()
+        .unencrypted_secret()
+        .with_policy(POLICY, None)
+        .supported()
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .next()
+    {
+        Some(key) => key.key().clone().into_keypair(),
+        None => Err(SpecialErrors::InvalidCert)?,
+    }
+}
+
+pub fn can_sign(cert: &Cert) -> bool {
+    signing_key(cert).is_ok()
+}
+
+fn sign(cert: &Cert, data: &[u8]) -> Result<Vec<u8>> {
+    let key = signing_key(cert)?;
+    let mut sink = Vec::new();
+    let message = sequoia_openpgp::serialize::stream::Message::new(&mut sink);
+    let message = Armorer::new(message).kind(armor::Kind::Signature).build()?;
+    let mut message = Signer::new(message, key)
+        .detached()
+        .hash_algo(HashAlgorithm::SHA256)?
+        .build()?;
+    message.write_all(data)?;
+    message.finalize()?;
+    Ok(sink)
+}
+
+fn sign_body(body: Body, cert: &Cert) -> Result<Body> {
+    // The CRLF preceding the next boundary belongs to the boundary, not to the signed part
+    let formatted = body.formatted();
+    let signature = log_err!(sign(cert, &formatted[..formatted.len() - 2]), warn)?;
+    let builder = MultiPart::signed(
+        "application/pgp-signature".to_string(),
+        "pgp-sha256".to_string(),
+    );
+    let builder = match body {
+        Body::Single(part) => builder.singlepart(part),
+        Body::Multi(part) => builder.multipart(part),
+    };
+    Ok(builder
+        .singlepart(
+            SinglePart::builder()
+                .header(
+                    ContentType::parse("application/pgp-signature; name=\"signature.asc\"")
+                        .unwrap(),
+                )
+                .body(String::from_utf8(signature)?),
+        )
+        .into())
+}
+
+fn encrypt_body(body: Body, cert: &Cert) -> Result<Body> {
+    let encrypted = log_err!(encrypt(cert, &body.formatted()), debug)?;
+    Ok(
+        MultiPart::encrypted("application/pgp-encrypted".to_string())
+            .singlepart(
+                SinglePart::builder()
+                    .header(ContentType::parse("application/pgp-encrypted").unwrap())
+                    .body("Version: 1".to_string()),
+            )
+            .singlepart(
+                SinglePart::builder()
+                    .header(
+                        ContentType::parse("application/octet-stream; name=\"encrypted.asc\"")
+                            .unwrap(),
+                    )
+                    .body(String::from_utf8(encrypted)?),
+            )
+            .into(),
+    )
+}
+
+pub fn build_mail(
+    builder: MessageBuilder,
+    body: impl Into<Body>,
+    recipient: Option<&Cert>,
+) -> Result<Message> {
+    let mut body = body.into();
+    if let Some(cert) = SERVER_KEY.as_ref() {
+        body = sign_body(body, cert)?;
+    }
+    if let Some(cert) = recipient {
+        body = encrypt_body(body, cert)?;
+    }
+    let email = match body {
+        Body::Single(part) => builder.singlepart(part),
+        Body::Multi(part) => builder.multipart(part),
+    };
+    let mut email = log_err!(email, warn)?;
+    if let Some(config) = DKIM.as_ref() {
+        email.sign(config);
+    }
+    Ok(email)
+}
@@ -1,72 +1,188 @@
+mod admin;
+mod assets;
+mod bounces;
+mod cli;
 mod confirmation;
 mod errors;
+mod i18n;
+mod mail;
 mod management;
+mod outbox;
+mod pow;
+mod proof;
+mod ratelimit;
+mod security;
 mod settings;
+mod templates;
 mod utils;
 
-use crate::confirmation::{confirm_action, send_confirmation_email};
+use crate::assets::resolve_page;
+use crate::cli::{Cli, Command};
+use crate::confirmation::{confirm_action, resend_confirmation, send_confirmation_email, Outcome};
 use crate::errors::CompatErr;
 use crate::errors::SpecialErrors;
-use crate::management::{clean_stale, store_pending_addition, store_pending_deletion, Action};
-use crate::settings::{ROOT_FOLDER, SETTINGS};
+use crate::i18n::{current_locale, negotiate, tr, translate_page, LOCALE};
+use crate::mail::can_encrypt;
+use crate::management::{
+    clean_stale, delete_pending, index_pending, publish_server_key, read_pending,
+    read_pending_token, store_pending_addition, store_pending_deletion, Action, Stored,
+};
+use crate::pow::{issue_challenge, prune_challenges, verify_work, Challenge, Work};
+use crate::proof::gen_challenge;
+use crate::ratelimit::{check_address, prune_buckets, RateLimit};
+use crate::security::{SameOrigin, SecurityHeaders};
+use crate::settings::{paths, set_paths, MAILER, SERVER_KEY, SETTINGS};
+use crate::templates::render_page;
 use crate::utils::{
-    gen_random_token, get_email_from_cert, is_email_allowed, key_exists, parse_pem, read_file,
-    return_outcome,
+    get_email_from_cert, is_email_allowed, parse_pem, read_key, return_items, return_outcome,
+    token_id,
 };
 
-use actix_files::Files;
-use actix_web::http::header::ContentType;
+use actix_files::{file_extension_to_mime, Files};
+use actix_web::dev::Service;
+use actix_web::http::header::{
+    ContentType, ACCEPT_LANGUAGE, CACHE_CONTROL, ETAG, IF_NONE_MATCH, LOCATION, VARY,
+};
 use actix_web::http::StatusCode;
 use actix_web::{
     get, post, web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Result,
 };
+use chrono::Utc;
+use clap::Parser;
 use log::{debug, error, info, trace};
-use serde::Deserialize;
+use minijinja::context;
+use once_cell::sync::Lazy;
+use sequoia_openpgp::Cert;
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use tokio::{task, time};
-use utils::{init_logger, pending_path, webpage_path};
+use utils::{
+    consumed_path, failed_mail_path, index_path, init_logger, moderation_path, outbox_path,
+    pending_path,
+};
 
 #[derive(Deserialize, Debug)]
 struct Key {
     key: String,
+    #[serde(flatten)]
+    work: Work,
 }
 
 #[derive(Deserialize, Debug)]
 struct Token {
     token: String,
+    signature: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Email {
     email: String,
+    #[serde(flatten)]
+    work: Work,
+}
+
+#[derive(Deserialize, Debug)]
+struct Resend {
+    email: String,
+    action: Action,
+}
+
+#[derive(Serialize, Debug)]
+struct RequestStatus {
+    action: Action,
+    address: String,
+    fingerprint: String,
+    expires: i64,
+    bounced: bool,
+    challenge: Option<String>,
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    if init_logger().is_err() {
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    set_paths(cli.paths);
+    let command = cli.command.unwrap_or(Command::Serve);
+    let serving = matches!(command, Command::Serve);
+    if init_logger(serving).is_err() {
         panic!("Could not set up logger!")
     };
+    if let Command::CheckConfig = command {
+        return cli::run(command).await;
+    }
     log_err!(fs::create_dir_all(pending_path()), error)?;
-    log_err!(fs::create_dir_all(PathBuf::from(ROOT_FOLDER).join(".well-known")), error)?;
+    log_err!(fs::create_dir_all(index_path()), error)?;
+    log_err!(fs::create_dir_all(moderation_path()), error)?;
+    log_err!(fs::create_dir_all(consumed_path()), error)?;
+    log_err!(fs::create_dir_all(outbox_path()), error)?;
+    log_err!(fs::create_dir_all(failed_mail_path()), error)?;
+    log_err!(
+        fs::create_dir_all(paths().data_dir.join(".well-known")),
+        error
+    )?;
+    if serving {
+        Lazy::force(&MAILER);
+        log_err!(index_pending(), error)?;
+        if let Some(cert) = SERVER_KEY.as_ref() {
+            publish_server_key(cert)?;
+        }
+        Ok(serve().await?)
+    } else {
+        cli::run(command).await
+    }
+}
+
+async fn serve() -> std::io::Result<()> {
     task::spawn(async {
         let mut metronome = time::interval(time::Duration::from_secs(SETTINGS.cleanup_interval));
         loop {
             metronome.tick().await;
             debug!("Cleaning up stale data...");
             clean_stale(SETTINGS.max_age);
+            prune_buckets();
+            prune_challenges();
             debug!("Cleanup completed!")
         }
     });
+    task::spawn(outbox::run_worker());
+    if let Some(settings) = SETTINGS.bounces.as_ref() {
+        task::spawn(async move {
+            let mut metronome = time::interval(time::Duration::from_secs(settings.interval));
+            loop {
+                metronome.tick().await;
+                debug!("Processing bounces...");
+                if let Ok(count) = bounces::process_bounces(settings) {
+                    debug!("Processed {} bounces", count);
+                }
+            }
+        });
+    }
     debug!("Starting server...");
     let server = HttpServer::new(|| {
         App::new()
+            .wrap(SecurityHeaders)
+            .wrap_fn(|req, srv| {
+                let locale = negotiate(
+                    req.headers()
+                        .get(ACCEPT_LANGUAGE)
+                        .and_then(|header| header.to_str().ok()),
+                );
+                // Middlewares may answer within the call already, so it runs in the scope too
+                let response = LOCALE.sync_scope(locale.clone(), || srv.call(req));
+                LOCALE.scope(locale, response)
+            })
             .service(submit)
             .service(confirm)
+            .service(confirm_signed)
+            .service(get_challenge)
+            .service(get_work)
             .service(delete)
+            .service(resend)
+            .service(cancel)
+            .service(request_status)
+            .service(admin::scope())
             .service(
-                Files::new("/.well-known", Path::new(&ROOT_FOLDER).join(".well-known"))
-                    .use_hidden_files(),
+                Files::new("/.well-known", paths().data_dir.join(".well-known")).use_hidden_files(),
             )
             .route("/{filename:.*}", web::get().to(index))
     })
@@ -80,27 +196,65 @@ async fn main() -> std::io::Result<()> {
 }
 
 async fn index(req: HttpRequest) -> Result<HttpResponse, CompatErr> {
-    let path = webpage_path().join(req.match_info().query("filename"));
-    for file in &["", "index.html"] {
-        let path = if file.is_empty() {
-            path.to_owned()
+    let filename = req.match_info().query("filename");
+    let Some((path, asset)) = resolve_page(filename) else {
+        trace!("The requested file {} could not be found", filename);
+        Err(SpecialErrors::MissingFile)?
+    };
+    let extension = Path::new(&path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    if extension != "html" {
+        let etag = format!("\"{}\"", asset.etag);
+        // Astro puts the bundled assets in _astro, naming them after their content
+        let cache = if filename.starts_with("_astro/") {
+            "public, max-age=31536000, immutable"
+        } else {
+            "public, max-age=3600"
+        };
+        let unchanged = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag));
+        let mut response = if unchanged {
+            HttpResponse::NotModified()
         } else {
-            path.join(file)
+            HttpResponse::Ok()
         };
-        if path.is_file() {
-            let template = log_err!(read_file(&path), error, true)?;
-            let page = template.replace("((%u))", SETTINGS.external_url.as_ref());
-            return Ok(HttpResponseBuilder::new(StatusCode::OK)
-                .insert_header(ContentType::html())
-                .body(page));
+        response
+            .insert_header((ETAG, etag))
+            .insert_header((CACHE_CONTROL, cache));
+        if unchanged {
+            return Ok(response.finish());
         }
+        return Ok(response
+            .insert_header(ContentType(file_extension_to_mime(extension)))
+            .body(asset.data.into_owned()));
     }
-    trace!("The requested file {} could not be found", path.display());
-    Err(SpecialErrors::MissingFile)?
+    let template = String::from_utf8(asset.data.into_owned()).map_err(anyhow::Error::from)?;
+    let locale = current_locale();
+    let page = translate_page(&template, &locale).replace("((%u))", SETTINGS.external_url.as_ref());
+    let page = render_page(
+        "page.html",
+        &page,
+        context! {
+            url => SETTINGS.external_url.as_str(),
+            locale => locale,
+        },
+    )?;
+    // Pages are translated according to the Accept-Language header
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header(ContentType::html())
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .insert_header((VARY, "Accept-Language"))
+        .body(page))
 }
 
-#[post("/api/submit")]
+#[post("/api/submit", wrap = "RateLimit", wrap = "SameOrigin")]
 async fn submit(pem: web::Form<Key>) -> Result<HttpResponse, CompatErr> {
+    verify_work(&pem.work)?;
     let cert = parse_pem(&pem.key)?;
     let validcert = validate_cert!(cert)?;
     if validcert.is_tsk() {
@@ -109,48 +263,213 @@ async fn submit(pem: web::Form<Key>) -> Result<HttpResponse, CompatErr> {
     let email = get_email_from_cert(&validcert)?;
     debug!("Handling user {} request to add a key...", email);
     is_email_allowed(&email)?;
-    let token = gen_random_token();
-    store_pending_addition(pem.key.clone(), &email, &token)?;
-    debug!(
-        "Sending email to {} to add a key... (Request token: {})",
-        email, token
-    );
-    send_confirmation_email(&email, &Action::Add, &token).await?;
+    check_address(&email)?;
+    check_recipient(&cert)?;
+    let challenge = gen_challenge();
+    let locale = current_locale();
+    let stored =
+        store_pending_addition(pem.key.clone(), &email, challenge.clone(), locale.clone())?;
+    let (message, challenge) = match stored {
+        Stored::Created(token) => {
+            debug!(
+                "Queueing email to {} to add a key... (Request: {})",
+                email,
+                token_id(&token)?
+            );
+            queue_confirmation(&email, &Action::Add, &token, &cert, &locale)?;
+            ("messages.submitted", challenge)
+        }
+        Stored::Existing(id) => ("messages.already_pending", existing_challenge(&id)?),
+    };
     info!("User {} requested to add a key successfully!", email);
-    Ok(return_outcome(Ok("You submitted your key successfully!"))?)
+    Ok(return_outcome(Ok(&with_challenge(
+        &tr(message),
+        challenge,
+    )))?)
+}
+
+// Requests are only stored if their confirmation can be sent
+fn check_recipient(cert: &Cert) -> anyhow::Result<()> {
+    if SETTINGS.mail_settings.encrypt && !can_encrypt(cert) {
+        debug!("Key {} can't receive encrypted mails", cert.fingerprint());
+        Err(SpecialErrors::NoEncryptionKey)?
+    }
+    Ok(())
+}
+
+fn queue_confirmation(
+    address: &str,
+    action: &Action,
+    token: &str,
+    cert: &Cert,
+    locale: &str,
+) -> anyhow::Result<()> {
+    if let Err(error) = send_confirmation_email(address, action, token, cert, locale) {
+        // Otherwise the request would be reused without ever being sent
+        delete_pending(&token_id(token)?)?;
+        Err(error)?
+    }
+    Ok(())
+}
+
+// The confirmation of an identical request was already sent, so it isn't sent again
+fn existing_challenge(id: &str) -> anyhow::Result<Option<String>> {
+    debug!("Request {} is already pending", id);
+    Ok(read_pending(id)?.challenge().map(ToString::to_string))
+}
+
+fn with_challenge(message: &str, challenge: Option<String>) -> String {
+    match challenge {
+        Some(challenge) => format!("{} {} {}", message, tr("messages.challenge"), challenge),
+        None => message.to_string(),
+    }
+}
+
+#[get("/api/challenge")]
+async fn get_challenge(token: web::Query<Token>) -> Result<HttpResponse, CompatErr> {
+    let (_, pending) = read_pending_token(&token.token)?;
+    match pending.challenge() {
+        Some(challenge) => Ok(HttpResponse::Ok()
+            .insert_header(ContentType::plaintext())
+            .body(challenge.to_string())),
+        None => Err(SpecialErrors::MissingFile)?,
+    }
+}
+
+#[get("/api/pow")]
+async fn get_work() -> Result<web::Json<Challenge>, CompatErr> {
+    Ok(web::Json(issue_challenge()?))
 }
 
 #[get("/api/confirm")]
-async fn confirm(token: web::Query<Token>) -> Result<HttpResponse, CompatErr> {
-    debug!("Handling token {}...", token.token);
-    let (action, email) = confirm_action(&token.token)?;
+async fn confirm(token: web::Query<Token>) -> HttpResponse {
+    // Links are only followed to the confirmation page, so that prefetchers can't confirm requests
+    let mut url = SETTINGS.external_url.join("confirm").unwrap();
+    url.query_pairs_mut().append_pair("token", &token.token);
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, url.as_str()))
+        .finish()
+}
+
+#[post("/api/confirm", wrap = "SameOrigin")]
+async fn confirm_signed(token: web::Form<Token>) -> Result<HttpResponse, CompatErr> {
+    handle_confirmation(&token)
+}
+
+fn handle_confirmation(token: &Token) -> Result<HttpResponse, CompatErr> {
+    debug!("Handling request {}...", token_id(&token.token)?);
+    let (action, email, outcome) = confirm_action(&token.token, token.signature.as_deref())?;
     info!(
         "User {} confirmed to {} his key successfully!",
         email,
         action.to_string().to_lowercase()
     );
-    match action {
-        Action::Add => Ok(return_outcome(Ok("Your key was added successfully!"))?),
-        Action::Delete => Ok(return_outcome(Ok("Your key was deleted successfully!"))?),
+    match (action, outcome) {
+        (_, Outcome::AwaitingApproval) => {
+            Ok(return_outcome(Ok(&tr("messages.awaiting_approval")))?)
+        }
+        (Action::Add, Outcome::Completed) => Ok(return_items(Ok(&tr("messages.added")), &[email])?),
+        (Action::Delete, Outcome::Completed) => {
+            Ok(return_items(Ok(&tr("messages.deleted")), &[email])?)
+        }
     }
 }
 
-#[get("/api/delete")]
-async fn delete(email: web::Query<Email>) -> Result<HttpResponse, CompatErr> {
+#[post("/api/delete", wrap = "RateLimit", wrap = "SameOrigin")]
+async fn delete(email: web::Form<Email>) -> Result<HttpResponse, CompatErr> {
     debug!("Handling user {} request to delete a key...", email.email);
-    key_exists(&email.email)?;
-    let token = gen_random_token();
-    store_pending_deletion(email.email.clone(), &token)?;
+    verify_work(&email.work)?;
+    check_address(&email.email)?;
+    let locale = current_locale();
+    if SETTINGS.hide_existence {
+        // Neither the response nor its timing may depend on whether a key exists
+        let address = email.email.clone();
+        task::spawn_blocking(move || {
+            if let Err(error) = request_deletion(&address, &locale) {
+                debug!("Request to delete the key of {} failed: {}", address, error);
+            }
+        });
+        return Ok(return_outcome(Ok(&tr(
+            "messages.deletion_maybe_requested",
+        )))?);
+    }
+    let (message, challenge) = request_deletion(&email.email, &locale)?;
+    Ok(return_outcome(Ok(&with_challenge(
+        &tr(message),
+        challenge,
+    )))?)
+}
+
+fn request_deletion(address: &str, locale: &str) -> anyhow::Result<(&'static str, Option<String>)> {
+    let cert = read_key(address)?;
+    check_recipient(&cert)?;
+    let challenge = gen_challenge();
+    let stored =
+        store_pending_deletion(address.to_string(), challenge.clone(), locale.to_string())?;
+    let outcome = match stored {
+        Stored::Created(token) => {
+            debug!(
+                "Queueing email to {} to delete a key... (Request: {})",
+                address,
+                token_id(&token)?
+            );
+            queue_confirmation(address, &Action::Delete, &token, &cert, locale)?;
+            ("messages.deletion_requested", challenge)
+        }
+        Stored::Existing(id) => ("messages.already_pending", existing_challenge(&id)?),
+    };
+    info!("User {} requested to delete his key successfully!", address);
+    Ok(outcome)
+}
+
+#[post("/api/resend", wrap = "RateLimit", wrap = "SameOrigin")]
+async fn resend(request: web::Form<Resend>) -> Result<HttpResponse, CompatErr> {
     debug!(
-        "Sending email to {} to delete a key... (Request token: {})",
-        email.email, token
+        "Handling user {} request to resend a confirmation...",
+        request.email
     );
-    send_confirmation_email(&email.email, &Action::Delete, &token).await?;
+    check_address(&request.email)?;
+    if SETTINGS.hide_existence {
+        let Resend { email, action } = request.into_inner();
+        task::spawn_blocking(move || match resend_confirmation(&email, action) {
+            Ok(_) => info!(
+                "User {} requested to resend a confirmation successfully!",
+                email
+            ),
+            Err(error) => debug!("Resending a confirmation to {} failed: {}", email, error),
+        });
+        return Ok(return_outcome(Ok(&tr("messages.maybe_resent")))?);
+    }
+    resend_confirmation(&request.email, request.action)?;
     info!(
-        "User {} requested to delete his key successfully!",
-        email.email
+        "User {} requested to resend a confirmation successfully!",
+        request.email
     );
-    Ok(return_outcome(Ok(
-        "You requested the deletion of your key successfully!",
-    ))?)
+    Ok(return_outcome(Ok(&tr("messages.resent")))?)
+}
+
+#[post("/api/cancel", wrap = "SameOrigin")]
+async fn cancel(token: web::Form<Token>) -> Result<HttpResponse, CompatErr> {
+    let (id, pending) = read_pending_token(&token.token)?;
+    let address = pending.address()?;
+    delete_pending(&id)?;
+    info!("User {} cancelled request {} successfully!", address, id);
+    Ok(return_outcome(Ok(&tr("messages.cancelled")))?)
+}
+
+#[get("/api/status")]
+async fn request_status(token: web::Query<Token>) -> Result<web::Json<RequestStatus>, CompatErr> {
+    let (_, pending) = read_pending_token(&token.token)?;
+    let expires = pending.timestamp() + SETTINGS.max_age;
+    if Utc::now().timestamp() > expires {
+        Err(SpecialErrors::ExpiredRequest)?
+    }
+    Ok(web::Json(RequestStatus {
+        action: *pending.action(),
+        address: pending.address()?,
+        fingerprint: pending.cert()?.fingerprint().to_spaced_hex(),
+        expires,
+        bounced: pending.bounce().is_some(),
+        challenge: pending.challenge().map(ToString::to_string),
+    }))
 }
@@ -1,14 +1,25 @@
-use crate::log_err;
-use crate::settings::ERROR_TEXT;
-use crate::utils::{email_to_file_path, pending_path, read_file};
+use crate::errors::SpecialErrors;
+use crate::i18n::default_locale;
+use crate::outbox::purge_failed;
+use crate::settings::{ERROR_TEXT, POLICY, SETTINGS};
+use crate::utils::{
+    constant_time_eq, consumed_path, email_to_file_path, flagged_path, gen_random_token,
+    get_email_from_cert, hash_token, index_path, insert_key, is_email_allowed, is_valid_id,
+    moderation_path, parse_pem, pending_path, published_path, read_file, read_key, sha256_hex,
+    token_id, ID_LENGTH,
+};
+use crate::{log_err, validate_cert};
 
 use anyhow::Result;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use log::{debug, warn};
+use sequoia_openpgp::{parse::Parse, Cert};
 use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::{fmt::Display, fs};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     Add,
     Delete,
@@ -20,27 +31,56 @@ impl Display for Action {
     }
 }
 
+impl Action {
+    const fn other(&self) -> Self {
+        match self {
+            Action::Add => Action::Delete,
+            Action::Delete => Action::Add,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pending {
     action: Action,
     data: String,
     timestamp: i64,
+    #[serde(default)]
+    challenge: Option<String>,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    bounce: Option<String>,
+    #[serde(default)]
+    last_sent: Option<i64>,
+    #[serde(default)]
+    digest: Option<String>,
 }
 impl Pending {
-    pub fn build_add(pem: String) -> Self {
+    pub fn build_add(pem: String, challenge: Option<String>, locale: String) -> Self {
         let timestamp = Utc::now().timestamp();
         Self {
             action: Action::Add,
             data: pem,
             timestamp,
+            challenge,
+            locale: Some(locale),
+            bounce: None,
+            last_sent: None,
+            digest: None,
         }
     }
-    pub fn build_delete(email: String) -> Self {
+    pub fn build_delete(email: String, challenge: Option<String>, locale: String) -> Self {
         let timestamp = Utc::now().timestamp();
         Self {
             action: Action::Delete,
             data: email,
             timestamp,
+            challenge,
+            locale: Some(locale),
+            bounce: None,
+            last_sent: None,
+            digest: None,
         }
     }
     pub const fn action(&self) -> &Action {
@@ -52,26 +92,437 @@ impl Pending {
     pub const fn timestamp(&self) -> i64 {
         self.timestamp
     }
+    pub fn challenge(&self) -> Option<&str> {
+        self.challenge.as_deref()
+    }
+    pub fn last_sent(&self) -> i64 {
+        self.last_sent.unwrap_or(self.timestamp)
+    }
+    pub fn bounce(&self) -> Option<&str> {
+        self.bounce.as_deref()
+    }
+    pub fn locale(&self) -> String {
+        self.locale.clone().unwrap_or_else(default_locale)
+    }
+    pub fn cert(&self) -> Result<Cert> {
+        match self.action {
+            Action::Add => parse_pem(&self.data),
+            Action::Delete => read_key(&self.data),
+        }
+    }
+    pub fn address(&self) -> Result<String> {
+        match self.action {
+            Action::Add => cert_email(&parse_pem(&self.data)?),
+            Action::Delete => Ok(self.data.clone()),
+        }
+    }
+    fn is_same(&self, other: &Self) -> bool {
+        if self.action != other.action {
+            return false;
+        }
+        match self.action {
+            // An updated key with the same fingerprint supersedes the pending one
+            Action::Add => match (parse_pem(&self.data), parse_pem(&other.data)) {
+                (Ok(cert), Ok(other)) => cert == other,
+                _ => false,
+            },
+            Action::Delete => self.data.eq_ignore_ascii_case(&other.data),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Stored {
+    /// A new request was stored, holding its token
+    Created(String),
+    /// An identical request is already pending, holding its id
+    Existing(String),
 }
 
-fn store_pending(pending: &Pending, token: &str) -> Result<()> {
+#[derive(Serialize, Deserialize, Debug)]
+struct Consumed {
+    digest: String,
+    timestamp: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PendingInfo {
+    pub id: String,
+    pub action: Action,
+    pub address: String,
+    pub timestamp: i64,
+    pub bounce: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FlaggedKey {
+    pub email: String,
+    pub reason: String,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FlaggedKeys {
+    #[serde(default)]
+    keys: Vec<FlaggedKey>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyInfo {
+    pub email: String,
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub valid: bool,
+}
+
+impl KeyInfo {
+    pub fn from_cert(cert: &Cert) -> Result<Self> {
+        let validcert = cert.with_policy(POLICY, None);
+        Ok(Self {
+            email: cert_email(cert)?,
+            fingerprint: cert.fingerprint().to_hex(),
+            user_ids: cert
+                .userids()
+                .map(|uid| String::from_utf8_lossy(uid.value()).into_owned())
+                .collect(),
+            created: DateTime::<Utc>::from(cert.primary_key().creation_time()).timestamp(),
+            expires: match &validcert {
+                Ok(validcert) => validcert
+                    .primary_key()
+                    .key_expiration_time()
+                    .map(|time| DateTime::<Utc>::from(time).timestamp()),
+                Err(_) => None,
+            },
+            valid: validcert.is_ok(),
+        })
+    }
+}
+
+fn cert_email(cert: &Cert) -> Result<String> {
+    match cert.with_policy(POLICY, None) {
+        Ok(validcert) => get_email_from_cert(&validcert),
+        Err(_) => match cert.userids().find_map(|uid| uid.email().ok().flatten()) {
+            Some(email) => Ok(email),
+            None => Err(SpecialErrors::EmailMissing)?,
+        },
+    }
+}
+
+fn request_path(dir: &Path, id: &str) -> Result<PathBuf> {
+    if !is_valid_id(id) {
+        Err(SpecialErrors::MissingFile)?
+    }
+    Ok(dir.join(id))
+}
+
+fn store_pending(pending: &Pending, id: &str) -> Result<()> {
     let serialized = log_err!(toml::to_string(pending), warn)?;
-    log_err!(fs::write(pending_path().join(token), serialized), warn)?;
+    log_err!(
+        fs::write(request_path(&pending_path(), id)?, serialized),
+        warn
+    )?;
+    Ok(())
+}
+
+fn store_with_token(mut pending: Pending) -> Result<String> {
+    // Only the digest of the token is stored, the token itself is sent to the user
+    let token = gen_random_token();
+    let digest = hash_token(&token)?;
+    let id = digest[..ID_LENGTH].to_string();
+    pending.digest = Some(digest);
+    store_pending(&pending, &id)?;
+    Ok(token)
+}
+
+// Pending requests are indexed by address and action, so that they can be found without parsing every key
+fn index_entry(address: &str, action: Action) -> Result<PathBuf> {
+    let key = format!("{}:{}", address.to_lowercase(), action);
+    Ok(index_path().join(sha256_hex(key.as_bytes())?))
+}
+
+fn index_request(address: &str, action: Action, id: &str) -> Result<()> {
+    log_err!(fs::write(index_entry(address, action)?, id), warn)?;
+    Ok(())
+}
+
+// Entries pointing to requests that were deleted or claimed since are ignored
+fn indexed_request(address: &str, action: Action) -> Option<String> {
+    let id = fs::read_to_string(index_entry(address, action).ok()?).ok()?;
+    request_path(&pending_path(), &id)
+        .is_ok_and(|path| path.is_file())
+        .then_some(id)
+}
+
+pub fn index_pending() -> Result<()> {
+    // The requests are sorted by age, so the latest of each address and action is indexed last
+    for request in list_pending() {
+        index_request(&request.address, request.action, &request.id)?;
+    }
+    Ok(())
+}
+
+fn is_live(id: &str, now: i64) -> bool {
+    read_pending(id).is_ok_and(|request| now - request.timestamp() <= SETTINGS.max_age)
+}
+
+fn store_request(pending: Pending, address: &str) -> Result<Stored> {
+    let now = Utc::now().timestamp();
+    let action = *pending.action();
+    if let Some(id) = indexed_request(address, action) {
+        let existing = read_pending(&id)?;
+        if now - existing.timestamp() <= SETTINGS.max_age && existing.is_same(&pending) {
+            debug!("Reusing request {} of user {}", id, address);
+            return Ok(Stored::Existing(id));
+        }
+        // Only the latest request for each address and action is kept
+        delete_pending(&id)?;
+        debug!("Request {} of user {} was superseded", id, address);
+    }
+    let other = indexed_request(address, action.other()).filter(|id| is_live(id, now));
+    if 1 + usize::from(other.is_some()) > SETTINGS.max_pending_per_address {
+        debug!("User {} has too many pending requests", address);
+        Err(SpecialErrors::TooManyPending)?
+    }
+    let token = store_with_token(pending)?;
+    index_request(address, action, &token_id(&token)?)?;
+    Ok(Stored::Created(token))
+}
+
+pub fn store_pending_addition(
+    pem: String,
+    email: &str,
+    challenge: Option<String>,
+    locale: String,
+) -> Result<Stored> {
+    store_request(Pending::build_add(pem, challenge, locale), email)
+}
+
+pub fn store_pending_deletion(
+    email: String,
+    challenge: Option<String>,
+    locale: String,
+) -> Result<Stored> {
+    let address = email.clone();
+    store_request(Pending::build_delete(email, challenge, locale), &address)
+}
+
+fn read_request(path: &PathBuf) -> Result<Pending> {
+    let content = log_err!(read_file(path), debug)?;
+    Ok(log_err!(toml::from_str::<Pending>(&content), warn)?)
+}
+
+fn delete_request(path: &PathBuf) -> Result<()> {
+    if !path.is_file() {
+        Err(SpecialErrors::MissingFile)?
+    }
+    log_err!(fs::remove_file(path), warn)?;
+    Ok(())
+}
+
+fn list_requests(dir: &Path) -> Vec<PendingInfo> {
+    let mut requests = Vec::new();
+    for path in fs::read_dir(dir).unwrap().flatten() {
+        let id = path.file_name().to_string_lossy().into_owned();
+        if !is_valid_id(&id) {
+            continue;
+        }
+        let request = match read_request(&path.path()) {
+            Ok(request) => request,
+            Err(_) => continue,
+        };
+        let address = match request.address() {
+            Ok(address) => address,
+            Err(error) => {
+                warn!("{} {}", ERROR_TEXT, error);
+                continue;
+            }
+        };
+        requests.push(PendingInfo {
+            id,
+            action: *request.action(),
+            address,
+            timestamp: request.timestamp(),
+            bounce: request.bounce().map(ToString::to_string),
+        });
+    }
+    requests.sort_by_key(|info| info.timestamp);
+    requests
+}
+
+pub fn read_pending(id: &str) -> Result<Pending> {
+    read_request(&request_path(&pending_path(), id)?)
+}
+
+fn matches_digest(stored: Option<&str>, digest: &str) -> bool {
+    stored.is_some_and(|stored| constant_time_eq(stored.as_bytes(), digest.as_bytes()))
+}
+
+fn is_consumed(id: &str, digest: &str) -> bool {
+    let claimed = claimed_path(id).and_then(|path| read_request(&path));
+    if claimed.is_ok_and(|request| matches_digest(request.digest.as_deref(), digest)) {
+        return true;
+    }
+    let consumed = request_path(&consumed_path(), id)
+        .and_then(|path| read_file(&path))
+        .and_then(|content| Ok(toml::from_str::<Consumed>(&content)?));
+    consumed.is_ok_and(|consumed| matches_digest(Some(&consumed.digest), digest))
+}
+
+pub fn read_pending_token(token: &str) -> Result<(String, Pending)> {
+    let digest = hash_token(token)?;
+    let id = digest[..ID_LENGTH].to_string();
+    let pending = match read_pending(&id) {
+        Ok(pending) => pending,
+        Err(error) if is_consumed(&id, &digest) => {
+            debug!("Request {} was already confirmed: {}", id, error);
+            Err(SpecialErrors::AlreadyConfirmed)?
+        }
+        Err(error) => Err(error)?,
+    };
+    if !matches_digest(pending.digest.as_deref(), &digest) {
+        Err(SpecialErrors::MissingFile)?
+    }
+    Ok((id, pending))
+}
+
+fn claimed_path(id: &str) -> Result<PathBuf> {
+    Ok(request_path(&pending_path(), id)?.with_extension("claimed"))
+}
+
+pub fn claim_pending(id: &str) -> Result<()> {
+    // Renaming is atomic, so only one of concurrent confirmations can claim the request
+    match fs::rename(request_path(&pending_path(), id)?, claimed_path(id)?) {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            debug!("Request {} was claimed by another confirmation", id);
+            Err(SpecialErrors::AlreadyConfirmed)?
+        }
+        Err(error) => log_err!(Err(error), warn)?,
+    }
+}
+
+pub fn release_pending(id: &str) -> Result<()> {
+    log_err!(
+        fs::rename(claimed_path(id)?, request_path(&pending_path(), id)?),
+        warn
+    )?;
     Ok(())
 }
 
-pub fn store_pending_addition(pem: String, _email: &str, token: &str) -> Result<()> {
-    let pending = Pending::build_add(pem);
-    store_pending(&pending, token)?;
+pub fn consume_pending(id: &str, request: &Pending) -> Result<()> {
+    let consumed = Consumed {
+        digest: request.digest.clone().unwrap_or_default(),
+        timestamp: request.timestamp(),
+    };
+    let serialized = log_err!(toml::to_string(&consumed), warn)?;
+    log_err!(
+        fs::write(request_path(&consumed_path(), id)?, serialized),
+        warn
+    )?;
+    let claimed = claimed_path(id)?;
+    if claimed.is_file() {
+        log_err!(fs::remove_file(claimed), warn)?;
+    }
     Ok(())
 }
 
-pub fn store_pending_deletion(email: String, token: &str) -> Result<()> {
-    let pending = Pending::build_delete(email);
-    store_pending(&pending, token)?;
+pub fn delete_pending(id: &str) -> Result<()> {
+    delete_request(&request_path(&pending_path(), id)?)
+}
+
+pub fn find_pending(address: &str, action: Action) -> Option<String> {
+    indexed_request(address, action)
+}
+
+pub fn touch_pending(id: &str) -> Result<()> {
+    let mut request = read_pending(id)?;
+    request.last_sent = Some(Utc::now().timestamp());
+    store_pending(&request, id)
+}
+
+pub fn rotate_pending(id: &str) -> Result<String> {
+    let pending = read_pending(id)?;
+    let (address, action) = (pending.address()?, *pending.action());
+    let token = store_with_token(pending)?;
+    index_request(&address, action, &token_id(&token)?)?;
+    delete_pending(id)?;
+    debug!("Request {} was moved to {}", id, token_id(&token)?);
+    Ok(token)
+}
+
+pub fn mark_bounced(id: &str, reason: &str) -> Result<()> {
+    let mut request = read_pending(id)?;
+    request.bounce = Some(reason.to_string());
+    store_pending(&request, id)
+}
+
+pub fn list_pending() -> Vec<PendingInfo> {
+    list_requests(&pending_path())
+}
+
+pub fn queue_for_moderation(id: &str) -> Result<()> {
+    log_err!(
+        fs::rename(claimed_path(id)?, request_path(&moderation_path(), id)?),
+        warn
+    )?;
     Ok(())
 }
 
+pub fn read_moderation(id: &str) -> Result<Pending> {
+    read_request(&request_path(&moderation_path(), id)?)
+}
+
+pub fn list_moderation() -> Vec<PendingInfo> {
+    list_requests(&moderation_path())
+}
+
+pub fn approve_request(id: &str) -> Result<String> {
+    let request = read_moderation(id)?;
+    let cert = parse_pem(request.data())?;
+    let validcert = validate_cert!(cert)?;
+    let email = get_email_from_cert(&validcert)?;
+    log_err!(insert_key(&validcert), warn)?;
+    delete_request(&request_path(&moderation_path(), id)?)?;
+    Ok(email)
+}
+
+pub fn reject_request(id: &str) -> Result<String> {
+    let email = read_moderation(id)?.address()?;
+    delete_request(&request_path(&moderation_path(), id)?)?;
+    Ok(email)
+}
+
+fn collect_keys(dir: &Path, keys: &mut Vec<KeyInfo>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("{} {}", ERROR_TEXT, error);
+            return;
+        }
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            collect_keys(&path, keys);
+        } else if path.parent().and_then(Path::file_name) == Some("hu".as_ref()) {
+            match Cert::from_file(&path).and_then(|cert| KeyInfo::from_cert(&cert)) {
+                Ok(info) => keys.push(info),
+                Err(error) => warn!("{} {}: {}", ERROR_TEXT, path.display(), error),
+            }
+        }
+    }
+}
+
+pub fn list_keys() -> Vec<KeyInfo> {
+    let mut keys = Vec::new();
+    if published_path().is_dir() {
+        collect_keys(&published_path(), &mut keys);
+    }
+    keys.sort_by(|a, b| a.email.cmp(&b.email));
+    keys
+}
+
 pub fn clean_stale(max_age: i64) {
     for path in fs::read_dir(pending_path()).unwrap().flatten() {
         let file_path = path.path();
@@ -97,6 +548,38 @@ pub fn clean_stale(max_age: i64) {
             };
         }
     }
+    for path in fs::read_dir(index_path()).unwrap().flatten() {
+        let file_path = path.path();
+        let dangling = fs::read_to_string(&file_path).is_ok_and(|id| {
+            request_path(&pending_path(), &id).is_ok_and(|request| !request.is_file())
+        });
+        if dangling {
+            match fs::remove_file(&file_path) {
+                Ok(_) => debug!("Deleted {}, since it was dangling", file_path.display()),
+                Err(error) => warn!("{} {}", ERROR_TEXT, error),
+            };
+        }
+    }
+    purge_failed(max_age);
+    // The tokens of consumed requests expire like the pending ones, so their markers can go too
+    for path in fs::read_dir(consumed_path()).unwrap().flatten() {
+        let file_path = path.path();
+        let consumed = match read_file(&file_path)
+            .and_then(|content| Ok(toml::from_str::<Consumed>(&content)?))
+        {
+            Ok(consumed) => consumed,
+            Err(error) => {
+                warn!("{} {}", ERROR_TEXT, error);
+                continue;
+            }
+        };
+        if Utc::now().timestamp() - consumed.timestamp > max_age {
+            match fs::remove_file(&file_path) {
+                Ok(_) => debug!("Deleted {}, since it was stale", file_path.display()),
+                Err(error) => warn!("{} {}", ERROR_TEXT, error),
+            };
+        }
+    }
 }
 
 pub fn delete_key(email: &str) -> Result<()> {
@@ -104,3 +587,67 @@ pub fn delete_key(email: &str) -> Result<()> {
     log_err!(fs::remove_file(path), warn)?;
     Ok(())
 }
+
+pub fn published_key(email: &str) -> Result<KeyInfo> {
+    is_email_allowed(email)?;
+    KeyInfo::from_cert(&read_key(email)?)
+}
+
+pub fn publish_server_key(cert: &Cert) -> Result<()> {
+    let validcert = validate_cert!(cert)?;
+    log_err!(insert_key(&validcert), warn)?;
+    debug!("Published the server key {}", cert.fingerprint());
+    Ok(())
+}
+
+pub fn publish_key(pem: &str) -> Result<KeyInfo> {
+    let cert = parse_pem(pem)?;
+    let validcert = validate_cert!(cert)?;
+    if validcert.is_tsk() {
+        Err(SpecialErrors::ContainsSecret)?
+    }
+    let email = get_email_from_cert(&validcert)?;
+    is_email_allowed(&email)?;
+    log_err!(insert_key(&validcert), warn)?;
+    KeyInfo::from_cert(&cert)
+}
+
+fn read_flagged() -> Result<Vec<FlaggedKey>> {
+    let path = flagged_path();
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let content = log_err!(read_file(&path), warn)?;
+    Ok(log_err!(toml::from_str::<FlaggedKeys>(&content), warn)?.keys)
+}
+
+fn store_flagged(keys: Vec<FlaggedKey>) -> Result<()> {
+    let serialized = log_err!(toml::to_string(&FlaggedKeys { keys }), warn)?;
+    log_err!(fs::write(flagged_path(), serialized), warn)?;
+    Ok(())
+}
+
+pub fn list_flagged() -> Result<Vec<FlaggedKey>> {
+    read_flagged()
+}
+
+pub fn flag_key(email: &str, reason: &str) -> Result<()> {
+    let mut keys = read_flagged()?;
+    keys.retain(|key| key.email != email);
+    keys.push(FlaggedKey {
+        email: email.to_string(),
+        reason: reason.to_string(),
+        timestamp: Utc::now().timestamp(),
+    });
+    store_flagged(keys)
+}
+
+pub fn unflag_key(email: &str) -> Result<()> {
+    let mut keys = read_flagged()?;
+    let count = keys.len();
+    keys.retain(|key| key.email != email);
+    if keys.len() == count {
+        Err(SpecialErrors::InexistingUser)?
+    }
+    store_flagged(keys)
+}
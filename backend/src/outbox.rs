@@ -0,0 +1,255 @@
+use crate::errors::SpecialErrors;
+use crate::log_err;
+use crate::settings::{ERROR_TEXT, MAILER, SETTINGS};
+use crate::utils::{failed_mail_path, gen_random_token, outbox_path, read_file};
+
+use anyhow::Result;
+use chrono::Utc;
+use lettre::address::Envelope;
+use lettre::{Address, Message};
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+static WAKEUP: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueuedMail {
+    from: Option<String>,
+    to: Vec<String>,
+    subject: String,
+    message: String,
+    created: i64,
+    attempts: u32,
+    next_attempt: i64,
+    last_error: Option<String>,
+}
+
+impl QueuedMail {
+    fn envelope(&self) -> Result<Envelope> {
+        let from = match &self.from {
+            Some(from) => Some(from.parse::<Address>()?),
+            None => None,
+        };
+        let to = self
+            .to
+            .iter()
+            .map(|to| to.parse::<Address>())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Envelope::new(from, to)?)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueuedMailInfo {
+    pub id: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub created: i64,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+    pub failed: bool,
+}
+
+fn store_mail(path: &Path, mail: &QueuedMail) -> Result<()> {
+    // Write to a temporary file first, so the worker never reads a partial message
+    let serialized = log_err!(toml::to_string(mail), warn)?;
+    let temporary = path.with_extension("tmp");
+    log_err!(fs::write(&temporary, serialized), warn)?;
+    log_err!(fs::rename(&temporary, path), warn)?;
+    Ok(())
+}
+
+fn read_mail(path: &Path) -> Result<QueuedMail> {
+    let content = log_err!(read_file(&path.to_path_buf()), debug)?;
+    Ok(log_err!(toml::from_str::<QueuedMail>(&content), warn)?)
+}
+
+fn list_mail(dir: &Path) -> Vec<(String, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("{} {}", ERROR_TEXT, error);
+            return Vec::new();
+        }
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_none())
+        .map(|path| {
+            (
+                path.file_name().unwrap().to_string_lossy().into_owned(),
+                path,
+            )
+        })
+        .collect()
+}
+
+pub fn queue_mail(email: &Message) -> Result<String> {
+    let envelope = email.envelope();
+    let now = Utc::now().timestamp();
+    let mail = QueuedMail {
+        from: envelope.from().map(ToString::to_string),
+        to: envelope.to().iter().map(ToString::to_string).collect(),
+        subject: email
+            .headers()
+            .get_raw("Subject")
+            .unwrap_or_default()
+            .to_string(),
+        message: String::from_utf8(email.formatted())?,
+        created: now,
+        attempts: 0,
+        next_attempt: now,
+        last_error: None,
+    };
+    let id = gen_random_token();
+    store_mail(&outbox_path().join(&id), &mail)?;
+    debug!("Queued email {} to {}", id, mail.to.join(", "));
+    WAKEUP.notify_one();
+    Ok(id)
+}
+
+async fn deliver(id: &str, path: &Path) -> Result<()> {
+    let mut mail = read_mail(path)?;
+    if mail.next_attempt > Utc::now().timestamp() {
+        return Ok(());
+    }
+    let result = match mail.envelope() {
+        Ok(envelope) => MAILER.send_raw(&envelope, mail.message.as_bytes()).await,
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(_) => {
+            log_err!(fs::remove_file(path), warn)?;
+            debug!("Delivered email {} to {}", id, mail.to.join(", "));
+        }
+        Err(error) => {
+            mail.attempts += 1;
+            mail.last_error = Some(error.to_string());
+            if mail.attempts >= SETTINGS.mail_settings.max_attempts {
+                // The links of expired emails can't be used anymore, so they aren't worth retrying
+                if !is_expired(&mail, SETTINGS.max_age) {
+                    store_mail(&failed_mail_path().join(id), &mail)?;
+                }
+                log_err!(fs::remove_file(path), warn)?;
+                warn!(
+                    "Giving up on email {} to {} after {} attempts: {}",
+                    id,
+                    mail.to.join(", "),
+                    mail.attempts,
+                    error
+                );
+            } else {
+                let backoff = SETTINGS
+                    .mail_settings
+                    .retry_delay
+                    .saturating_mul(2_u64.saturating_pow(mail.attempts - 1));
+                // Waiting longer than the confirmation links are valid is pointless
+                let backoff = i64::try_from(backoff)
+                    .unwrap_or(i64::MAX)
+                    .min(SETTINGS.max_age.max(1));
+                mail.next_attempt = Utc::now().timestamp().saturating_add(backoff);
+                store_mail(path, &mail)?;
+                debug!(
+                    "Delivery of email {} failed, retrying in {} seconds: {}",
+                    id, backoff, error
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_expired(mail: &QueuedMail, max_age: i64) -> bool {
+    Utc::now().timestamp() - mail.created > max_age
+}
+
+// Failed emails contain the tokens of their requests, so they are only kept while these are valid
+pub fn purge_failed(max_age: i64) {
+    for (id, path) in list_mail(&failed_mail_path()) {
+        if read_mail(&path).is_ok_and(|mail| is_expired(&mail, max_age)) {
+            match fs::remove_file(&path) {
+                Ok(_) => debug!("Deleted failed email {}, since it was stale", id),
+                Err(error) => warn!("{} {}", ERROR_TEXT, error),
+            };
+        }
+    }
+}
+
+pub async fn process_queue() {
+    for (id, path) in list_mail(&outbox_path()) {
+        if deliver(&id, &path).await.is_err() {
+            warn!("Unable to process queued email {}", id);
+        }
+    }
+}
+
+pub async fn run_worker() {
+    let interval = Duration::from_secs(SETTINGS.mail_settings.retry_delay.max(1));
+    loop {
+        process_queue().await;
+        let _ = time::timeout(interval, WAKEUP.notified()).await;
+    }
+}
+
+fn mail_info(id: String, mail: QueuedMail, failed: bool) -> QueuedMailInfo {
+    QueuedMailInfo {
+        id,
+        to: mail.to,
+        subject: mail.subject,
+        created: mail.created,
+        attempts: mail.attempts,
+        next_attempt: mail.next_attempt,
+        last_error: mail.last_error,
+        failed,
+    }
+}
+
+pub fn list_outbox() -> Vec<QueuedMailInfo> {
+    let mut mails = Vec::new();
+    for (dir, failed) in [(outbox_path(), false), (failed_mail_path(), true)] {
+        for (id, path) in list_mail(&dir) {
+            if let Ok(mail) = read_mail(&path) {
+                mails.push(mail_info(id, mail, failed));
+            }
+        }
+    }
+    mails.sort_by_key(|mail| mail.created);
+    mails
+}
+
+fn find_mail(id: &str) -> Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Err(SpecialErrors::MissingFile)?
+    }
+    [outbox_path(), failed_mail_path()]
+        .into_iter()
+        .map(|dir| dir.join(id))
+        .find(|path| path.is_file())
+        .ok_or_else(|| SpecialErrors::MissingFile.into())
+}
+
+pub fn retry_mail(id: &str) -> Result<()> {
+    let path = find_mail(id)?;
+    let mut mail = read_mail(&path)?;
+    mail.attempts = 0;
+    mail.next_attempt = Utc::now().timestamp();
+    store_mail(&outbox_path().join(id), &mail)?;
+    if path.starts_with(failed_mail_path()) {
+        log_err!(fs::remove_file(&path), warn)?;
+    }
+    debug!("Email {} was queued again", id);
+    WAKEUP.notify_one();
+    Ok(())
+}
+
+pub fn delete_mail(id: &str) -> Result<()> {
+    log_err!(fs::remove_file(find_mail(id)?), warn)?;
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use crate::errors::SpecialErrors;
+use crate::settings::SETTINGS;
+use crate::utils::constant_time_eq;
+
+use anyhow::Result;
+use chrono::Utc;
+use log::debug;
+use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sequoia_openpgp::types::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// How many seconds an issued challenge can be solved and used
+const VALIDITY: i64 = 600;
+const BLOCK_SIZE: usize = 64;
+
+// Challenges are signed instead of stored, so issuing them costs nothing but a hash
+static SECRET: Lazy<Vec<u8>> = Lazy::new(|| {
+    let mut rng = thread_rng();
+    (0..32).map(|_| rng.gen()).collect()
+});
+// Only the challenges that were already used are remembered, until they expire
+static SPENT: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Debug)]
+pub struct Challenge {
+    challenge: Option<String>,
+    difficulty: u8,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Work {
+    pow_challenge: Option<String>,
+    pow_nonce: Option<String>,
+}
+
+fn sha256(parts: &[&[u8]]) -> Result<Vec<u8>> {
+    let mut digest = vec![0; 32];
+    let mut algo = HashAlgorithm::SHA256.context()?;
+    for part in parts {
+        algo.update(part);
+    }
+    algo.digest(&mut digest)?;
+    Ok(digest)
+}
+
+// HMAC-SHA256 as defined in RFC 2104, with a key shorter than a block
+fn sign(message: &str) -> Result<String> {
+    let mut inner = [0x36; BLOCK_SIZE];
+    let mut outer = [0x5c; BLOCK_SIZE];
+    for (index, byte) in SECRET.iter().enumerate() {
+        inner[index] ^= byte;
+        outer[index] ^= byte;
+    }
+    let digest = sha256(&[&inner, message.as_bytes()])?;
+    let mac = sha256(&[&outer, &digest])?;
+    Ok(mac.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub fn issue_challenge() -> Result<Challenge> {
+    let Some(difficulty) = SETTINGS.pow_difficulty else {
+        return Ok(Challenge {
+            challenge: None,
+            difficulty: 0,
+        });
+    };
+    let mut rng = thread_rng();
+    let random: String = (0..16).map(|_| rng.sample(Alphanumeric) as char).collect();
+    let message = format!("{}-{}", Utc::now().timestamp(), random);
+    let challenge = format!("{}-{}", message, sign(&message)?);
+    Ok(Challenge {
+        challenge: Some(challenge),
+        difficulty,
+    })
+}
+
+// Returns when the challenge was issued, if it was issued by this server
+fn issued_at(challenge: &str) -> Option<i64> {
+    let (message, mac) = challenge.rsplit_once('-')?;
+    let expected = sign(message).ok()?;
+    if !constant_time_eq(mac.as_bytes(), expected.as_bytes()) {
+        return None;
+    }
+    message.split_once('-')?.0.parse().ok()
+}
+
+pub fn prune_challenges() {
+    let now = Utc::now().timestamp();
+    SPENT
+        .lock()
+        .unwrap()
+        .retain(|_, issued| now - *issued < VALIDITY);
+}
+
+fn leading_zeros(challenge: &str, nonce: &str) -> Result<u32> {
+    let digest = sha256(&[format!("{}:{}", challenge, nonce).as_bytes()])?;
+    Ok(count_zeros(&digest))
+}
+
+fn count_zeros(digest: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in digest {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+pub fn verify_work(work: &Work) -> Result<()> {
+    let Some(difficulty) = SETTINGS.pow_difficulty else {
+        return Ok(());
+    };
+    let (Some(challenge), Some(nonce)) = (&work.pow_challenge, &work.pow_nonce) else {
+        debug!("The proof of work is missing");
+        Err(SpecialErrors::InvalidWork)?
+    };
+    let issued = issued_at(challenge).filter(|issued| Utc::now().timestamp() - issued < VALIDITY);
+    let Some(issued) = issued else {
+        debug!("The proof of work challenge is unknown or expired");
+        Err(SpecialErrors::InvalidWork)?
+    };
+    if nonce.len() > 32 || leading_zeros(challenge, nonce)? < u32::from(difficulty) {
+        debug!("The proof of work does not meet the difficulty");
+        Err(SpecialErrors::InvalidWork)?
+    }
+    // Every challenge can only be used once, and only valid work is remembered
+    if SPENT
+        .lock()
+        .unwrap()
+        .insert(challenge.clone(), issued)
+        .is_some()
+    {
+        debug!("The proof of work challenge was already used");
+        Err(SpecialErrors::InvalidWork)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_zero_bits_across_bytes() {
+        assert_eq!(count_zeros(&[0xff, 0x00]), 0);
+        assert_eq!(count_zeros(&[0x80]), 0);
+        assert_eq!(count_zeros(&[0x7f]), 1);
+        assert_eq!(count_zeros(&[0x01, 0x00]), 7);
+        assert_eq!(count_zeros(&[0x00, 0x80]), 8);
+        assert_eq!(count_zeros(&[0x00, 0x01, 0x00]), 15);
+        assert_eq!(count_zeros(&[0x00, 0x00, 0xff]), 16);
+        assert_eq!(count_zeros(&[0x00; 32]), 256);
+    }
+
+    #[test]
+    fn hashes_challenge_and_nonce() {
+        // SHA-256("challenge:20") starts with 0x008d, SHA-256("challenge:15") with 0x04
+        assert_eq!(leading_zeros("challenge", "20").unwrap(), 8);
+        assert_eq!(leading_zeros("challenge", "15").unwrap(), 5);
+    }
+
+    #[test]
+    fn only_accepts_signed_challenges() {
+        let message = format!("{}-{}", 1700000000, "random");
+        let challenge = format!("{}-{}", message, sign(&message).unwrap());
+        assert_eq!(issued_at(&challenge), Some(1700000000));
+        let forged = challenge.replacen("1700000000", "1700000001", 1);
+        assert_eq!(issued_at(&forged), None);
+        assert_eq!(issued_at("1700000000-random"), None);
+        assert_eq!(issued_at(""), None);
+    }
+}
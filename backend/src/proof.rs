@@ -0,0 +1,82 @@
+use crate::errors::SpecialErrors;
+use crate::log_err;
+use crate::settings::{POLICY, SETTINGS};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sequoia_openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper, VerifierBuilder,
+};
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::{Cert, KeyHandle};
+use std::io::Read;
+
+struct Helper<'a> {
+    cert: &'a Cert,
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(vec![self.cert.clone()])
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+        for layer in structure.into_iter() {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                if results.iter().any(|result| result.is_ok()) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(anyhow!("No valid signature made by the certificate"))
+    }
+}
+
+pub fn gen_challenge() -> Option<String> {
+    if !SETTINGS.proof_of_possession {
+        return None;
+    }
+    let mut rng = thread_rng();
+    let nonce: String = (0..32).map(|_| rng.sample(Alphanumeric) as char).collect();
+    Some(format!("simple-wkd-{}", nonce))
+}
+
+fn verify_message(cert: &Cert, challenge: &str, signature: &str) -> Result<()> {
+    let mut verifier = VerifierBuilder::from_bytes(signature.as_bytes())?.with_policy(
+        POLICY,
+        None,
+        Helper { cert },
+    )?;
+    let mut content = String::new();
+    verifier.read_to_string(&mut content)?;
+    if content.trim() != challenge {
+        Err(anyhow!("The signed message does not match the challenge"))?
+    }
+    Ok(())
+}
+
+fn verify_detached(cert: &Cert, challenge: &str, signature: &str) -> Result<()> {
+    let mut verifier = DetachedVerifierBuilder::from_bytes(signature.as_bytes())?.with_policy(
+        POLICY,
+        None,
+        Helper { cert },
+    )?;
+    verifier
+        .verify_bytes(challenge)
+        .or_else(|_| verifier.verify_bytes(format!("{}\n", challenge)))
+}
+
+pub fn verify_challenge(cert: &Cert, challenge: &str, signature: &str) -> Result<()> {
+    let result = if signature.contains("-----BEGIN PGP SIGNATURE-----")
+        && !signature.contains("-----BEGIN PGP SIGNED MESSAGE-----")
+    {
+        verify_detached(cert, challenge, signature)
+    } else {
+        verify_message(cert, challenge, signature)
+    };
+    match log_err!(result, debug) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(SpecialErrors::InvalidSignature)?,
+    }
+}
@@ -0,0 +1,227 @@
+use crate::errors::{CompatErr, SpecialErrors};
+use crate::settings::{BucketSettings, SETTINGS};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use anyhow::Result;
+use log::debug;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Instant;
+
+static IP_LIMITER: Lazy<Limiter> = Lazy::new(|| Limiter::new(SETTINGS.rate_limit.per_ip));
+static ADDRESS_LIMITER: Lazy<Limiter> = Lazy::new(|| Limiter::new(SETTINGS.rate_limit.per_address));
+static GLOBAL_LIMITER: Lazy<Limiter> = Lazy::new(|| Limiter::new(SETTINGS.rate_limit.global));
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limiter {
+    settings: Option<BucketSettings>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    fn new(settings: Option<BucketSettings>) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns how many seconds to wait if the bucket of `key` is empty
+    fn take(&self, key: &str) -> Result<(), i64> {
+        let Some(settings) = self.settings else {
+            return Ok(());
+        };
+        let capacity = f64::from(settings.capacity);
+        let interval = settings.interval.max(1) as f64;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed / interval).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) * interval).ceil() as i64)
+        }
+    }
+
+    fn prune(&self) {
+        let Some(settings) = self.settings else {
+            return;
+        };
+        let capacity = f64::from(settings.capacity);
+        let interval = settings.interval.max(1) as f64;
+        let now = Instant::now();
+        // Full buckets are the same as missing ones
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() / interval < capacity
+        });
+    }
+}
+
+pub fn prune_buckets() {
+    IP_LIMITER.prune();
+    ADDRESS_LIMITER.prune();
+    GLOBAL_LIMITER.prune();
+}
+
+pub fn check_address(address: &str) -> Result<()> {
+    if let Err(seconds) = ADDRESS_LIMITER.take(&address.to_lowercase()) {
+        debug!("Requests for user {} were rate limited", address);
+        Err(SpecialErrors::RateLimited(seconds))?
+    }
+    Ok(())
+}
+
+fn forwarded_ip(peer: IpAddr, forwarded: &str, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    // Every trusted proxy appends the address it received the request from
+    forwarded
+        .rsplit(',')
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .find(|address| !trusted.contains(address))
+        .unwrap_or(peer)
+}
+
+fn client_ip(req: &ServiceRequest) -> Option<IpAddr> {
+    let forwarded = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default();
+    Some(forwarded_ip(
+        req.peer_addr()?.ip(),
+        forwarded,
+        &SETTINGS.rate_limit.trusted_proxies,
+    ))
+}
+
+fn check_client(req: &ServiceRequest) -> Result<(), i64> {
+    if let Some(ip) = client_ip(req) {
+        if let Err(seconds) = IP_LIMITER.take(&ip.to_string()) {
+            debug!("Requests from {} were rate limited", ip);
+            return Err(seconds);
+        }
+    }
+    if let Err(seconds) = GLOBAL_LIMITER.take("") {
+        debug!("Requests were rate limited globally");
+        return Err(seconds);
+    }
+    Ok(())
+}
+
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        match check_client(&req) {
+            Ok(_) => {
+                let response = self.service.call(req);
+                Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+            }
+            Err(seconds) => {
+                let error = CompatErr::from(SpecialErrors::RateLimited(seconds));
+                Box::pin(ready(Ok(req.error_response(error).map_into_right_body())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_header_from_untrusted_peers() {
+        let trusted = [ip("10.0.0.1")];
+        assert_eq!(
+            forwarded_ip(ip("203.0.113.7"), "198.51.100.1", &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn takes_last_untrusted_hop() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        // The first addresses are set by the client, so they can't be trusted
+        let forwarded = "192.0.2.66, 198.51.100.1, 10.0.0.2";
+        assert_eq!(
+            forwarded_ip(ip("10.0.0.1"), forwarded, &trusted),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            forwarded_ip(ip("10.0.0.1"), "2001:db8::1,10.0.0.2", &trusted),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn skips_malformed_hops() {
+        let trusted = [ip("10.0.0.1")];
+        assert_eq!(
+            forwarded_ip(ip("10.0.0.1"), "198.51.100.1, unknown", &trusted),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_peer() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), "", &trusted), ip("10.0.0.1"));
+        assert_eq!(
+            forwarded_ip(ip("10.0.0.1"), "10.0.0.2", &trusted),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::errors::{CompatErr, SpecialErrors};
+use crate::settings::SETTINGS;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE, ORIGIN, REFERER,
+    REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use actix_web::Error;
+use log::debug;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use url::Url;
+
+fn is_same_origin(req: &ServiceRequest) -> bool {
+    let expected = SETTINGS.external_url.origin();
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let matches = |value: &str| Url::parse(value).is_ok_and(|url| url.origin() == expected);
+    // Requests that hide where they come from are rejected as well
+    match (header(ORIGIN), header(REFERER)) {
+        (Some(origin), _) => matches(origin),
+        (None, Some(referer)) => matches(referer),
+        (None, None) => false,
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap) {
+    let settings = &SETTINGS.security_headers;
+    let configured: [(HeaderName, &str); 5] = [
+        (CONTENT_SECURITY_POLICY, &settings.content_security_policy),
+        (X_FRAME_OPTIONS, &settings.frame_options),
+        (REFERRER_POLICY, &settings.referrer_policy),
+        (
+            STRICT_TRANSPORT_SECURITY,
+            &settings.strict_transport_security,
+        ),
+        (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    ];
+    for (name, value) in configured {
+        if value.is_empty() {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+pub struct SameOrigin;
+
+impl<S, B> Transform<S, ServiceRequest> for SameOrigin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = SameOriginMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SameOriginMiddleware { service }))
+    }
+}
+
+pub struct SameOriginMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for SameOriginMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if is_same_origin(&req) {
+            let response = self.service.call(req);
+            Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+        } else {
+            debug!("Rejected a request to {} from another origin", req.path());
+            // Rendered here, so that the outer middlewares handle the error page like any response
+            let response = req.error_response(CompatErr::from(SpecialErrors::CrossOrigin));
+            Box::pin(ready(Ok(response.map_into_right_body())))
+        }
+    }
+}
+
+pub struct SecurityHeaders;
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware { service }))
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let is_html = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("text/html"));
+            if is_html {
+                insert_headers(response.headers_mut());
+            }
+            Ok(response)
+        })
+    }
+}
@@ -1,11 +1,28 @@
-use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
-use log::{debug, error};
-use once_cell::sync::Lazy;
+use anyhow::{anyhow, Result};
+use clap::Args;
+use lettre::message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, Tokio1Executor};
+use log::{debug, error, info};
+use once_cell::sync::{Lazy, OnceCell};
+use sequoia_openpgp::cert::CertBuilder;
+use sequoia_openpgp::packet::UserID;
+use sequoia_openpgp::parse::Parse;
 use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::serialize::Serialize as _;
+use sequoia_openpgp::Cert;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use url::Url;
 
+use crate::mail::{can_sign, Mailer};
 use crate::{log_err, utils::read_file};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -17,18 +34,139 @@ pub struct Settings {
     pub port: u16,
     pub bind_host: String,
     pub external_url: Url,
+    #[serde(default)]
+    pub proof_of_possession: bool,
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    #[serde(default)]
+    pub domains: HashMap<String, DomainSettings>,
+    #[serde(default = "default_resend_interval")]
+    pub resend_interval: i64,
+    #[serde(default = "default_max_pending")]
+    pub max_pending_per_address: usize,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub hide_existence: bool,
+    pub pow_difficulty: Option<u8>,
+    #[serde(default)]
+    pub security_headers: HeaderSettings,
+    pub assets_dir: Option<PathBuf>,
+    #[serde(default = "fallback_locale")]
+    pub default_locale: String,
+    pub bounces: Option<BounceSettings>,
     pub mail_settings: MailSettings,
 }
 
+fn default_resend_interval() -> i64 {
+    300
+}
+
+fn default_max_pending() -> usize {
+    2
+}
+
+fn fallback_locale() -> String {
+    "en".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BounceSettings {
+    pub maildir: PathBuf,
+    #[serde(default = "default_bounce_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub flag_keys: bool,
+}
+
+fn default_bounce_interval() -> u64 {
+    300
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RateLimitSettings {
+    pub per_ip: Option<BucketSettings>,
+    pub per_address: Option<BucketSettings>,
+    pub global: Option<BucketSettings>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BucketSettings {
+    pub capacity: u32,
+    pub interval: u64,
+}
+
+// Empty values disable the header
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct HeaderSettings {
+    pub content_security_policy: String,
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub strict_transport_security: String,
+}
+
+impl Default for HeaderSettings {
+    fn default() -> Self {
+        Self {
+            content_security_policy: "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; img-src 'self' data:; frame-ancestors 'none'; base-uri 'none'; form-action 'self'".to_string(),
+            frame_options: "DENY".to_string(),
+            referrer_policy: "same-origin".to_string(),
+            strict_transport_security: String::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DomainSettings {
+    #[serde(default)]
+    pub approval: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MailSettings {
+    #[serde(default)]
+    pub transport: MailTransport,
+    #[serde(default)]
     pub smtp_host: String,
-    pub smtp_username: String,
-    pub smtp_password: String,
-    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
     pub smtp_tls: SMTPEncryption,
+    pub smtp_timeout: Option<u64>,
+    pub sendmail_command: Option<PathBuf>,
+    pub mail_dir: Option<PathBuf>,
     pub mail_from: String,
-    pub mail_subject: String,
+    pub mail_subject: Option<String>,
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default)]
+    pub sign: bool,
+    pub signing_key: Option<PathBuf>,
+    pub dkim: Option<DkimSettings>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    8
+}
+
+fn default_retry_delay() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DkimSettings {
+    pub selector: String,
+    pub domain: String,
+    pub private_key: PathBuf,
+    pub algorithm: DkimAlgorithm,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -37,58 +175,244 @@ pub enum Variant {
     Direct,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub enum MailTransport {
+    #[default]
+    Smtp,
+    Sendmail,
+    File,
+    Stdout,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub enum SMTPEncryption {
+    #[default]
     Tls,
     Starttls,
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DkimAlgorithm {
+    Rsa,
+    Ed25519,
+}
+
+#[derive(Args, Debug)]
+pub struct Paths {
+    /// The configuration file
+    #[arg(
+        long,
+        global = true,
+        env = "SIMPLE_WKD_CONFIG",
+        default_value = "config.toml"
+    )]
+    pub config: PathBuf,
+    /// Where the keys, requests and emails are stored
+    #[arg(
+        long,
+        global = true,
+        env = "SIMPLE_WKD_DATA_DIR",
+        default_value = "data"
+    )]
+    pub data_dir: PathBuf,
+    /// A folder overriding the embedded assets, taking precedence over `assets_dir` in the configuration
+    #[arg(long, global = true, env = "SIMPLE_WKD_ASSETS_DIR")]
+    pub assets_dir: Option<PathBuf>,
+    /// Where the log files are written
+    #[arg(
+        long,
+        global = true,
+        env = "SIMPLE_WKD_LOG_DIR",
+        default_value = "logs"
+    )]
+    pub log_dir: PathBuf,
+}
+
+pub fn set_paths(paths: Paths) {
+    PATHS.set(paths).expect("The paths can only be set once");
+}
+
+pub fn paths() -> &'static Paths {
+    PATHS.get().expect("The paths are set on startup")
+}
+
+// Relative paths in the configuration file are relative to the folder containing it
+fn resolve(base: &Path, path: &mut PathBuf) {
+    if path.is_relative() {
+        *path = base.join(&*path);
+    }
+}
+
+pub fn load_settings() -> Result<Settings> {
+    let config = &paths().config;
+    let content = read_file(config)?;
+    let mut settings: Settings = toml::from_str(&content)?;
+    let base = config.parent().unwrap_or(Path::new(""));
+    let mail_settings = &mut settings.mail_settings;
+    for path in [
+        settings.assets_dir.as_mut(),
+        settings
+            .bounces
+            .as_mut()
+            .map(|bounces| &mut bounces.maildir),
+        mail_settings.mail_dir.as_mut(),
+        mail_settings.signing_key.as_mut(),
+        mail_settings
+            .dkim
+            .as_mut()
+            .map(|dkim| &mut dkim.private_key),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        resolve(base, path);
+    }
+    Ok(settings)
 }
 
 fn get_settings() -> Settings {
     debug!("Parsing settings...");
-    let content = match read_file(&PathBuf::from("config.toml")) {
-        Ok(content) => content,
+    match log_err!(load_settings(), error) {
+        Ok(settings) => settings,
         Err(_) => {
-            error!("Unable to access settings file!");
-            panic!("Unable to access settings file!")
+            error!("Unable to load settings from file!");
+            panic!("Unable to load settings from file!")
+        }
+    }
+}
+
+fn build_smtp(settings: &MailSettings) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    if settings.smtp_host.is_empty() {
+        Err(anyhow!("No smtp host was configured"))?
+    }
+    let mut builder = match &settings.smtp_tls {
+        SMTPEncryption::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.smtp_host)?,
+        SMTPEncryption::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.smtp_host)?
+        }
+        SMTPEncryption::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.smtp_host)
         }
     };
-    match log_err!(toml::from_str(&content), error) {
-        Ok(settings) => settings,
+    if let (Some(username), Some(password)) = (&settings.smtp_username, &settings.smtp_password) {
+        builder = builder.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+    }
+    if let Some(port) = settings.smtp_port {
+        builder = builder.port(port);
+    }
+    if let Some(timeout) = settings.smtp_timeout {
+        builder = builder.timeout(Some(Duration::from_secs(timeout)));
+    }
+    Ok(builder.build())
+}
+
+pub fn build_mailer(settings: &MailSettings) -> Result<Mailer> {
+    Ok(match settings.transport {
+        MailTransport::Smtp => Mailer::Smtp(build_smtp(settings)?),
+        MailTransport::Sendmail => Mailer::Sendmail(match &settings.sendmail_command {
+            Some(command) => AsyncSendmailTransport::new_with_command(command),
+            None => AsyncSendmailTransport::new(),
+        }),
+        MailTransport::File => {
+            let path = match &settings.mail_dir {
+                Some(path) => path.to_owned(),
+                None => paths().data_dir.join("mail"),
+            };
+            fs::create_dir_all(&path)?;
+            Mailer::File(AsyncFileTransport::new(path))
+        }
+        MailTransport::Stdout => Mailer::Stdout,
+    })
+}
+
+fn get_mailer() -> Mailer {
+    debug!("Setting up the mail transport...");
+    match log_err!(build_mailer(&SETTINGS.mail_settings), error) {
+        Ok(mailer) => mailer,
         Err(_) => {
-            error!("Unable to parse settings from file!");
-            panic!("Unable to parse settings from file!")
+            error!("Unable to set up the mail transport");
+            panic!("Unable to set up the mail transport")
         }
     }
 }
 
-fn get_mailer() -> AsyncSmtpTransport<Tokio1Executor> {
-    debug!("Setting up smtp...");
-    let creds = Credentials::new(
-        SETTINGS.mail_settings.smtp_username.to_owned(),
-        SETTINGS.mail_settings.smtp_password.to_owned(),
-    );
-    let builder = match &SETTINGS.mail_settings.smtp_tls {
-        SMTPEncryption::Tls => {
-            AsyncSmtpTransport::<Tokio1Executor>::relay(&SETTINGS.mail_settings.smtp_host)
+fn load_server_key(path: &Path) -> Result<Cert> {
+    if path.is_file() {
+        debug!("Loading server key from {}...", path.display());
+        return Cert::from_file(path);
+    }
+    info!("Generating server key in {}...", path.display());
+    let mailbox: Mailbox = SETTINGS.mail_settings.mail_from.parse()?;
+    let userid = UserID::from_address(mailbox.name.as_deref(), None, mailbox.email.as_ref())?;
+    let (cert, _) = CertBuilder::general_purpose(None, Some(userid))
+        .set_validity_period(None)
+        .generate()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(path)?;
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    cert.as_tsk().armored().serialize(&mut file)?;
+    Ok(cert)
+}
+
+fn get_server_key() -> Option<Cert> {
+    if !SETTINGS.mail_settings.sign {
+        return None;
+    }
+    let path = match &SETTINGS.mail_settings.signing_key {
+        Some(path) => path.to_owned(),
+        None => paths().data_dir.join("server-key.pgp"),
+    };
+    let cert = match log_err!(load_server_key(&path), error) {
+        Ok(cert) => cert,
+        Err(_) => {
+            error!("Unable to set up the server key");
+            panic!("Unable to set up the server key")
         }
-        SMTPEncryption::Starttls => {
-            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&SETTINGS.mail_settings.smtp_host)
+    };
+    // Otherwise every email would fail to be signed
+    if !can_sign(&cert) {
+        error!("The server key has no valid signing key without a password!");
+        panic!("The server key has no valid signing key without a password!")
+    }
+    Some(cert)
+}
+
+fn get_dkim() -> Option<DkimConfig> {
+    let settings = SETTINGS.mail_settings.dkim.as_ref()?;
+    debug!("Setting up dkim...");
+    let algorithm = match settings.algorithm {
+        DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+        DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+    };
+    let key = match log_err!(read_file(&settings.private_key), error) {
+        Ok(key) => key,
+        Err(_) => {
+            error!("Unable to read the dkim private key!");
+            panic!("Unable to read the dkim private key!")
         }
     };
-    match builder {
-        Ok(builder) => builder,
+    let key = match log_err!(DkimSigningKey::new(key.trim(), algorithm), error) {
+        Ok(key) => key,
         Err(_) => {
-            error!("Unable to set up smtp");
-            panic!("Unable to set up smtp")
+            error!("Unable to parse the dkim private key!");
+            panic!("Unable to parse the dkim private key!")
         }
-    }
-    .credentials(creds)
-    .port(SETTINGS.mail_settings.smtp_port)
-    .build()
+    };
+    Some(DkimConfig::default_config(
+        settings.selector.to_owned(),
+        settings.domain.to_owned(),
+        key,
+    ))
 }
 
 pub const ERROR_TEXT: &str = "An error occoured:";
 pub const POLICY: &StandardPolicy = &StandardPolicy::new();
-pub const ROOT_FOLDER: &str = "data";
+pub static PATHS: OnceCell<Paths> = OnceCell::new();
 pub static SETTINGS: Lazy<Settings> = Lazy::new(get_settings);
-pub static MAILER: Lazy<AsyncSmtpTransport<Tokio1Executor>> = Lazy::new(get_mailer);
+pub static SERVER_KEY: Lazy<Option<Cert>> = Lazy::new(get_server_key);
+pub static DKIM: Lazy<Option<DkimConfig>> = Lazy::new(get_dkim);
+pub static MAILER: Lazy<Mailer> = Lazy::new(get_mailer);
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use minijinja::syntax::SyntaxConfig;
+use minijinja::{Environment, Value};
+use once_cell::sync::Lazy;
+
+// The web pages are built with astro, which reserves braces, so they use parentheses instead
+static PAGE_SYNTAX: Lazy<SyntaxConfig> = Lazy::new(|| {
+    SyntaxConfig::builder()
+        .block_delimiters("((%", "%))")
+        .variable_delimiters("((=", "))")
+        .comment_delimiters("((#", "#))")
+        .build()
+        .unwrap()
+});
+
+// Values are HTML escaped if the name of the template ends with `.html`
+fn render<'a>(
+    mut env: Environment<'a>,
+    name: &'a str,
+    source: &'a str,
+    context: Value,
+) -> Result<String> {
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env.add_template(name, source)?;
+    Ok(env.get_template(name)?.render(context)?)
+}
+
+pub fn render_page(name: &str, source: &str, context: Value) -> Result<String> {
+    let mut env = Environment::new();
+    env.set_syntax(PAGE_SYNTAX.clone());
+    render(env, name, source, context)
+}
+
+pub fn render_mail(name: &str, source: &str, context: Value) -> Result<String> {
+    render(Environment::new(), name, source, context)
+}
@@ -1,9 +1,12 @@
+use crate::assets::read_asset;
 use crate::errors::CompatErr;
 use crate::errors::SpecialErrors;
+use crate::i18n::{current_locale, tr_in, translate_page};
 use crate::log_err;
+use crate::settings::paths;
 use crate::settings::Variant;
-use crate::settings::ROOT_FOLDER;
 use crate::settings::SETTINGS;
+use crate::templates::render_page;
 
 use actix_web::ResponseError;
 use actix_web::{
@@ -12,17 +15,14 @@ use actix_web::{
 };
 use anyhow::Result;
 use flexi_logger::{style, DeferredNow, FileSpec, FlexiLoggerError, Logger, LoggerHandle, Record};
-use log::debug;
-use log::error;
+use log::{debug, error, warn};
+use minijinja::context;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use sequoia_openpgp::cert::ValidCert;
 use sequoia_openpgp::serialize::Marshal;
 use sequoia_openpgp::types::HashAlgorithm;
 use sequoia_openpgp::{parse::Parse, Cert};
-use std::{
-    fs,
-    path::{Path, PathBuf},
-};
+use std::{fs, path::PathBuf};
 
 #[macro_export]
 macro_rules! validate_cert {
@@ -57,7 +57,7 @@ pub fn email_to_file_path(email: &str) -> Result<PathBuf> {
         Variant::Direct => format!(".well-known/openpgpkey/hu/{}", local_encoded),
     };
 
-    Ok(PathBuf::from(ROOT_FOLDER).join(directory))
+    Ok(paths().data_dir.join(directory))
 }
 
 pub fn insert_key(cert: &ValidCert) -> Result<()> {
@@ -70,17 +70,59 @@ pub fn insert_key(cert: &ValidCert) -> Result<()> {
     fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .open(path.parent().unwrap().parent().unwrap().join("policy"))?;
 
     Ok(())
 }
 
+pub fn read_key(email: &str) -> Result<Cert> {
+    let path = email_to_file_path(email)?;
+    if !path.is_file() {
+        debug!("No key found for user {}", email);
+        Err(SpecialErrors::InexistingUser)?
+    }
+    Ok(log_err!(Cert::from_file(path), warn)?)
+}
+
+pub fn published_path() -> PathBuf {
+    paths().data_dir.join(".well-known").join("openpgpkey")
+}
+
 pub fn pending_path() -> PathBuf {
-    Path::new(&ROOT_FOLDER).join("pending")
+    paths().data_dir.join("pending")
 }
 
-pub fn webpage_path() -> PathBuf {
-    Path::new("assets").join("webpage")
+pub fn index_path() -> PathBuf {
+    paths().data_dir.join("index")
+}
+
+pub fn moderation_path() -> PathBuf {
+    paths().data_dir.join("moderation")
+}
+
+pub fn consumed_path() -> PathBuf {
+    paths().data_dir.join("consumed")
+}
+
+pub fn outbox_path() -> PathBuf {
+    paths().data_dir.join("outbox")
+}
+
+pub fn failed_mail_path() -> PathBuf {
+    paths().data_dir.join("failed")
+}
+
+pub fn flagged_path() -> PathBuf {
+    paths().data_dir.join("flagged.toml")
+}
+
+pub fn requires_approval(email: &str) -> Result<bool> {
+    let domain = extract_domain(email)?;
+    Ok(SETTINGS
+        .domains
+        .get(&domain)
+        .is_some_and(|settings| settings.approval))
 }
 
 pub fn read_file(path: &PathBuf) -> Result<String> {
@@ -110,9 +152,50 @@ pub fn parse_pem(pemfile: &str) -> Result<Cert> {
     Ok(cert)
 }
 
+// 32 alphanumeric characters carry about 190 bits of entropy
+pub const TOKEN_LENGTH: usize = 32;
+// Requests are stored under a prefix of the token digest, the full digest is checked on lookup
+pub const ID_LENGTH: usize = 32;
+
 pub fn gen_random_token() -> String {
     let mut rng = thread_rng();
-    (0..10).map(|_| rng.sample(Alphanumeric) as char).collect()
+    (0..TOKEN_LENGTH)
+        .map(|_| rng.sample(Alphanumeric) as char)
+        .collect()
+}
+
+pub fn is_valid_token(token: &str) -> bool {
+    token.len() == TOKEN_LENGTH && token.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LENGTH && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+pub fn hash_token(token: &str) -> Result<String> {
+    if !is_valid_token(token) {
+        Err(SpecialErrors::InvalidToken)?
+    }
+    sha256_hex(token.as_bytes())
+}
+
+pub fn sha256_hex(data: &[u8]) -> Result<String> {
+    let mut digest = vec![0; 32];
+    let mut algo = HashAlgorithm::SHA256.context()?;
+    algo.update(data);
+    algo.digest(&mut digest)?;
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub fn token_id(token: &str) -> Result<String> {
+    Ok(hash_token(token)?[..ID_LENGTH].to_string())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 pub fn get_email_from_cert(cert: &ValidCert) -> Result<String> {
@@ -125,7 +208,7 @@ pub fn get_email_from_cert(cert: &ValidCert) -> Result<String> {
 }
 
 pub fn extract_domain(email: &str) -> Result<String> {
-    let domain = match email.split('@').last() {
+    let domain = match email.split('@').next_back() {
         Some(domain) => domain.to_string(),
         None => {
             debug!("Unable to extract domain from {}, email malformed", email);
@@ -135,15 +218,6 @@ pub fn extract_domain(email: &str) -> Result<String> {
     Ok(domain)
 }
 
-pub fn key_exists(email: &str) -> Result<bool> {
-    let path = email_to_file_path(email)?;
-    if !path.is_file() {
-        debug!("No key found for user {}", email);
-        Err(SpecialErrors::InexistingUser)?
-    }
-    Ok(true)
-}
-
 pub fn custom_color_format(
     w: &mut dyn std::io::Write,
     now: &mut DeferredNow,
@@ -156,7 +230,7 @@ pub fn custom_color_format(
         style(level).paint(now.format("%Y-%m-%d %H:%M:%S").to_string()),
         style(level).paint(record.module_path().unwrap_or("<unnamed>")),
         style(level).paint(record.level().to_string()),
-        style(level).paint(&record.args().to_string())
+        style(level).paint(record.args().to_string())
     )
 }
 
@@ -192,10 +266,15 @@ pub fn custom_file_format(
     )
 }
 
-pub fn init_logger() -> Result<LoggerHandle, FlexiLoggerError> {
+pub fn init_logger(to_stdout: bool) -> Result<LoggerHandle, FlexiLoggerError> {
+    let duplicate = if to_stdout {
+        flexi_logger::Duplicate::All
+    } else {
+        flexi_logger::Duplicate::None
+    };
     Logger::try_with_env_or_str("simple_wkd=debug")?
-        .log_to_file(FileSpec::default().directory("logs"))
-        .duplicate_to_stdout(flexi_logger::Duplicate::All)
+        .log_to_file(FileSpec::default().directory(&paths().log_dir))
+        .duplicate_to_stdout(duplicate)
         .format_for_files(custom_file_format)
         .adaptive_format_for_stdout(flexi_logger::AdaptiveFormat::Custom(
             custom_monochrome_format,
@@ -206,18 +285,32 @@ pub fn init_logger() -> Result<LoggerHandle, FlexiLoggerError> {
 }
 
 pub fn return_outcome(data: Result<&str, &CompatErr>) -> Result<HttpResponse> {
-    let path = webpage_path().join("status").join("index.html");
-    let template = log_err!(read_file(&path), error, true)?;
-    let (page, message) = match data {
-        Ok(message) => (template.replace("((%s))", "Success!"), message.to_string()),
-        Err(error) => (template.replace("((%s))", "Failure!"), error.to_string()),
+    return_items(data, &[])
+}
+
+pub fn return_items(data: Result<&str, &CompatErr>, items: &[String]) -> Result<HttpResponse> {
+    let template = log_err!(read_asset("webpage/status/index.html"), error)?;
+    let locale = current_locale();
+    let template = translate_page(&template, &locale);
+    let (status, message) = match data {
+        Ok(message) => (tr_in(&locale, "status.success"), message.to_string()),
+        Err(error) => (tr_in(&locale, "status.failure"), error.localized()),
     };
     let status_code = match data {
         Ok(_) => StatusCode::OK,
         Err(error) => error.status_code(),
     };
-    let page = page.replace("((%m))", &message);
-    return Ok(HttpResponseBuilder::new(status_code)
+    let page = render_page(
+        "status.html",
+        &template,
+        context! {
+            success => data.is_ok(),
+            status => status,
+            message => message,
+            items => items,
+        },
+    )?;
+    Ok(HttpResponseBuilder::new(status_code)
         .insert_header(ContentType::html())
-        .body(page));
+        .body(page))
 }